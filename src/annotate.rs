@@ -0,0 +1,244 @@
+use crate::ReadFilter;
+use anyhow::Error;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::{Format, Header, HeaderView, Read, Reader, Record, Writer};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// What happened to a read, as written in the XS tag of --output-bam. The
+// names follow featureCounts -R BAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assignment {
+    Assigned,
+    Unmapped,
+    MapQ,
+    Flag,
+    // Rejected by the filter expression
+    Filter,
+    NoFeature,
+    Ambiguous,
+}
+
+impl Assignment {
+    pub fn status(&self) -> &'static str {
+        match self {
+            Assignment::Assigned => "Assigned",
+            Assignment::Unmapped => "Unassigned_Unmapped",
+            Assignment::MapQ => "Unassigned_MapQ",
+            Assignment::Flag => "Unassigned_Flag",
+            Assignment::Filter => "Unassigned_Filter",
+            Assignment::NoFeature => "Unassigned_NoFeature",
+            Assignment::Ambiguous => "Unassigned_Ambiguous",
+        }
+    }
+
+    // Assignment of a read by the features it overlaps
+    pub fn from_features(features: &[&str]) -> Self {
+        match features.len() {
+            0 => Assignment::NoFeature,
+            1 => Assignment::Assigned,
+            _ => Assignment::Ambiguous,
+        }
+    }
+}
+
+// Why a read fails a filter, checked in the same order as ReadFilter::check.
+// Unmapped reads placed at the position of their mate are reported as
+// unmapped rather than by the filter they fail.
+pub fn filter_assignment(filter: &ReadFilter, read: &Record) -> Option<Assignment> {
+    if read.is_unmapped() {
+        Some(Assignment::Unmapped)
+    } else if read.mapq() < filter.minmapqual {
+        Some(Assignment::MapQ)
+    } else if read.flags() & filter.required_flag != filter.required_flag
+        || read.flags() & filter.filtered_flag != 0
+    {
+        Some(Assignment::Flag)
+    } else {
+        None
+    }
+}
+
+// Assignment and features of the primary alignments of split long reads, by
+// read name, for tagging their supplementary alignments
+pub type PrimaryAssignments = HashMap<Vec<u8>, (Assignment, Vec<String>)>;
+
+const FLAG_SUPPLEMENTARY: u16 = 2048;
+
+// Whether a tag may hold an assignment: it is absent, or a string as written
+// by featureCounts or an earlier run. Tags of other types belong to the
+// aligner, e.g. the XS:A strand tag of STAR and HISAT2 or the XS:i and XT:A
+// tags of BWA, and are kept.
+fn is_replaceable(read: &Record, tag: &[u8]) -> bool {
+    matches!(read.aux(tag), Err(_) | Ok(Aux::String(_)))
+}
+
+// Sets the assignment of a read in XS and its features, comma-separated, in
+// XT, unless XS is an aligner tag. XT is also left alone if it is one.
+pub fn tag_assignment(
+    read: &mut Record,
+    assignment: Assignment,
+    features: &[&str],
+) -> Result<(), Error> {
+    if !is_replaceable(read, b"XS") {
+        return Ok(());
+    }
+    read.remove_aux(b"XS").ok();
+    read.push_aux(b"XS", Aux::String(assignment.status()))?;
+    if is_replaceable(read, b"XT") {
+        read.remove_aux(b"XT").ok();
+        if !features.is_empty() {
+            read.push_aux(b"XT", Aux::String(&features.join(",")))?;
+        }
+    }
+    Ok(())
+}
+
+// Writes the reads of one part of an output BAM file: one chromosome, or
+// the unmapped reads. Parts are written in parallel to temporary files next
+// to the output, then concatenated in header order.
+pub struct PartWriter {
+    writer: Writer,
+}
+
+pub fn part_path(output: &Path, part: usize) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(format!(".part{}.tmp", part));
+    PathBuf::from(path)
+}
+
+impl PartWriter {
+    pub fn create(output: &Path, part: usize, header: &HeaderView) -> Result<Self, Error> {
+        let writer = Writer::from_path(
+            part_path(output, part),
+            &Header::from_template(header),
+            Format::Bam,
+        )?;
+        Ok(PartWriter { writer })
+    }
+
+    // Writes a copy of the read, tagged with its assignment if given
+    pub fn write(
+        &mut self,
+        read: &Record,
+        assignment: Option<Assignment>,
+        features: &[&str],
+    ) -> Result<(), Error> {
+        let mut read = read.clone();
+        if let Some(assignment) = assignment {
+            tag_assignment(&mut read, assignment, features)?;
+        }
+        self.writer.write(&read)?;
+        Ok(())
+    }
+}
+
+// Passes the reads of the parts that were written, in order, to `f` and
+// removes the parts
+pub fn read_parts(
+    output: &Path,
+    parts: usize,
+    mut f: impl FnMut(&Record) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut read = Record::new();
+    for part in 0..parts {
+        let path = part_path(output, part);
+        if !path.exists() {
+            continue;
+        }
+        let mut reader = Reader::from_path(&path)?;
+        while let Some(result) = reader.read(&mut read) {
+            result?;
+            f(&read)?;
+        }
+        std::fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+// Concatenates the parts that were written, in order, into the output file.
+// Supplementary alignments of the `primaries` are tagged like their primary
+// alignment, which may be on another chromosome.
+pub fn concatenate_parts(
+    output: &Path,
+    parts: usize,
+    header: &HeaderView,
+    primaries: &PrimaryAssignments,
+) -> Result<(), Error> {
+    let mut writer = Writer::from_path(output, &Header::from_template(header), Format::Bam)?;
+    read_parts(output, parts, |read| {
+        let primary = match read.flags() & FLAG_SUPPLEMENTARY {
+            0 => None,
+            _ => primaries.get(read.qname()),
+        };
+        match primary {
+            Some((assignment, features)) => {
+                let mut read = read.clone();
+                let features: Vec<_> = features.iter().map(|f| f.as_str()).collect();
+                tag_assignment(&mut read, *assignment, &features)?;
+                Ok(writer.write(&read)?)
+            }
+            None => Ok(writer.write(read)?),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assignment() {
+        let filter = ReadFilter::default();
+        let mut read = Record::new();
+        read.set_mapq(10);
+        read.set_flags(3);
+        assert_eq!(filter_assignment(&filter, &read), Some(Assignment::MapQ));
+        read.set_mapq(60);
+        assert_eq!(filter_assignment(&filter, &read), None);
+        read.set_flags(3 | 256);
+        assert_eq!(filter_assignment(&filter, &read), Some(Assignment::Flag));
+        read.set_mapq(0);
+        read.set_flags(1 | 4 | 8 | 64);
+        assert_eq!(
+            filter_assignment(&filter, &read),
+            Some(Assignment::Unmapped)
+        );
+
+        assert_eq!(
+            Assignment::from_features(&[]).status(),
+            "Unassigned_NoFeature"
+        );
+        assert_eq!(Assignment::from_features(&["g1"]).status(), "Assigned");
+        assert_eq!(
+            Assignment::from_features(&["g1", "g2"]).status(),
+            "Unassigned_Ambiguous"
+        );
+        assert_eq!(
+            part_path(Path::new("out/counted.bam"), 3),
+            PathBuf::from("out/counted.bam.part3.tmp")
+        );
+    }
+
+    #[test]
+    fn test_tag_assignment_keeps_aligner_tags() {
+        let mut read = Record::new();
+        tag_assignment(&mut read, Assignment::Assigned, &["g1", "g2"]).unwrap();
+        assert_eq!(read.aux(b"XS").unwrap(), Aux::String("Assigned"));
+        assert_eq!(read.aux(b"XT").unwrap(), Aux::String("g1,g2"));
+        // An earlier assignment is replaced
+        tag_assignment(&mut read, Assignment::NoFeature, &[]).unwrap();
+        assert_eq!(
+            read.aux(b"XS").unwrap(),
+            Aux::String("Unassigned_NoFeature")
+        );
+        assert!(read.aux(b"XT").is_err());
+
+        // The strand tag of a spliced aligner is kept
+        let mut read = Record::new();
+        read.push_aux(b"XS", Aux::Char(b'+')).unwrap();
+        tag_assignment(&mut read, Assignment::Assigned, &["g1"]).unwrap();
+        assert_eq!(read.aux(b"XS").unwrap(), Aux::Char(b'+'));
+        assert!(read.aux(b"XT").is_err());
+    }
+}
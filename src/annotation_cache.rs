@@ -0,0 +1,416 @@
+use crate::io::GtfFile;
+use crate::regions::{Gene, Region};
+use crate::transcripts::Transcript;
+use anyhow::Error;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const CACHE_MAGIC: &[u8; 8] = b"RCANNOT\0";
+// Bumped whenever the encoding or the parsing of the annotation changes, so
+// that older caches are rebuilt rather than misread
+const CACHE_VERSION: u32 = 2;
+
+// Parsed annotations stored in a directory, one file per GTF content and
+// kind of table, so that repeated runs against the same annotation skip
+// parsing it. The GTF is identified by a hash of its bytes, not its path,
+// and of the regions its loading is restricted to, if any.
+pub struct AnnotationCache {
+    dir: PathBuf,
+    key: String,
+}
+
+impl AnnotationCache {
+    pub fn open(dir: &Path, gtf: &GtfFile) -> Result<Self, Error> {
+        std::fs::create_dir_all(dir)?;
+        let mut key = format!("{:016x}", content_hash(gtf.open_raw()?)?);
+        if let Some(regions) = gtf.tabix_regions()? {
+            let mut hash = FNV_OFFSET;
+            for region in regions {
+                let region = format!("{}:{}-{};", region.seqname, region.start, region.end);
+                hash = fnv1a(hash, region.as_bytes());
+            }
+            key.push_str(&format!("-{:016x}", hash));
+        }
+        Ok(AnnotationCache {
+            dir: dir.to_path_buf(),
+            key,
+        })
+    }
+
+    fn path(&self, kind: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}.v{}.bin", self.key, kind, CACHE_VERSION))
+    }
+
+    // Exon and gene regions as from GtfFile::exon_and_gene_regions
+    pub fn exon_and_gene_regions(&self, gtf: &GtfFile) -> Result<(Vec<Region>, Vec<Gene>), Error> {
+        self.load_or_build(
+            "regions",
+            |decoder| Ok((decoder.regions()?, decoder.genes()?)),
+            |encoder, (exons, genes)| {
+                encoder.regions(exons);
+                encoder.genes(genes);
+            },
+            || gtf.exon_and_gene_regions(),
+        )
+    }
+
+    // Transcripts as from GtfFile::transcripts
+    pub fn transcripts(&self, gtf: &GtfFile) -> Result<Vec<Transcript>, Error> {
+        self.load_or_build(
+            "transcripts",
+            |decoder| decoder.transcripts(),
+            |encoder, transcripts| encoder.transcripts(transcripts),
+            || gtf.transcripts(),
+        )
+    }
+
+    // Loads a table from its cache file, or builds it and writes the file.
+    // An unreadable or outdated file is rebuilt. The file is written under a
+    // temporary name and renamed, so that concurrent runs never read a
+    // partly written cache.
+    fn load_or_build<T>(
+        &self,
+        kind: &str,
+        decode: impl FnOnce(&mut Decoder) -> Result<T, Error>,
+        encode: impl FnOnce(&mut Encoder, &T),
+        build: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let path = self.path(kind);
+        if path.exists() {
+            match std::fs::read(&path)
+                .map_err(Error::from)
+                .and_then(|data| decode(&mut Decoder::new(&data)?))
+            {
+                Ok(value) => {
+                    eprintln!("Loaded annotation {} from {}", kind, path.display());
+                    return Ok(value);
+                }
+                Err(e) => eprintln!(
+                    "Warning: ignoring annotation cache {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        let value = build()?;
+        let mut encoder = Encoder::new();
+        encode(&mut encoder, &value);
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(format!(".{}.tmp", std::process::id()));
+        std::fs::write(&temp, encoder.finish())?;
+        std::fs::rename(&temp, &path)?;
+        eprintln!("Cached annotation {} in {}", kind, path.display());
+        Ok(value)
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+// FNV-1a, continued over more bytes
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn content_hash(mut reader: impl Read) -> Result<u64, Error> {
+    let mut hash = FNV_OFFSET;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        hash = fnv1a(hash, &buffer[..n]);
+    }
+}
+
+// Little-endian, length-prefixed encoding of annotation tables, after the
+// magic bytes and version. Chromosome names are stored once in a table and
+// referred to by index.
+struct Encoder {
+    data: Vec<u8>,
+    chroms: HashMap<String, u32>,
+    chrom_names: Vec<String>,
+}
+
+impl Encoder {
+    fn new() -> Self {
+        Encoder {
+            data: vec![],
+            chroms: HashMap::new(),
+            chrom_names: vec![],
+        }
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u64(value.len() as u64);
+        self.data.extend_from_slice(value.as_bytes());
+    }
+
+    fn chrom(&mut self, name: &str) {
+        let next = self.chrom_names.len() as u32;
+        let index = *self.chroms.entry(name.to_string()).or_insert_with(|| {
+            self.chrom_names.push(name.to_string());
+            next
+        });
+        self.u64(index as u64);
+    }
+
+    fn intervals(&mut self, intervals: &[(i64, i64)]) {
+        self.u64(intervals.len() as u64);
+        for &(start, end) in intervals {
+            self.i64(start);
+            self.i64(end);
+        }
+    }
+
+    fn region(&mut self, region: &Region) {
+        self.chrom(&region.seqname);
+        self.i64(region.start);
+        self.i64(region.end);
+    }
+
+    fn regions(&mut self, regions: &[Region]) {
+        self.u64(regions.len() as u64);
+        for region in regions {
+            self.region(region);
+        }
+    }
+
+    // Genes are stored in order, so their index is their position
+    fn genes(&mut self, genes: &[Gene]) {
+        self.u64(genes.len() as u64);
+        for gene in genes {
+            self.str(&gene.id);
+            match &gene.biotype {
+                Some(biotype) => {
+                    self.u64(1);
+                    self.str(biotype);
+                }
+                None => self.u64(0),
+            }
+            self.region(&gene.region);
+            self.intervals(&gene.exons);
+        }
+    }
+
+    fn transcripts(&mut self, transcripts: &[Transcript]) {
+        self.u64(transcripts.len() as u64);
+        for transcript in transcripts {
+            self.str(&transcript.id);
+            self.str(&transcript.gene_id);
+            self.chrom(&transcript.seqname);
+            self.intervals(&transcript.exons);
+        }
+    }
+
+    // The header and chromosome table followed by the encoded tables
+    fn finish(self) -> Vec<u8> {
+        let mut header = Encoder::new();
+        header.data.extend_from_slice(CACHE_MAGIC);
+        header.data.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+        header.u64(self.chrom_names.len() as u64);
+        for name in &self.chrom_names {
+            header.str(name);
+        }
+        header.data.extend_from_slice(&self.data);
+        header.data
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    chrom_names: Vec<String>,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let mut decoder = Decoder {
+            data,
+            chrom_names: vec![],
+        };
+        if decoder.bytes(CACHE_MAGIC.len())? != CACHE_MAGIC {
+            return Err(Error::msg("not an annotation cache"));
+        }
+        let version = u32::from_le_bytes(decoder.bytes(4)?.try_into()?);
+        if version != CACHE_VERSION {
+            return Err(Error::msg(format!("cache version {}", version)));
+        }
+        let n = decoder.len()?;
+        for _ in 0..n {
+            let name = decoder.str()?;
+            decoder.chrom_names.push(name);
+        }
+        Ok(decoder)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() < n {
+            return Err(Error::msg("truncated annotation cache"));
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, Error> {
+        Ok(i64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    // A length, checked against the remaining data so that a corrupt length
+    // cannot cause a huge allocation
+    fn len(&mut self) -> Result<usize, Error> {
+        let len = self.u64()?;
+        if len > self.data.len() as u64 {
+            return Err(Error::msg("truncated annotation cache"));
+        }
+        Ok(len as usize)
+    }
+
+    fn str(&mut self) -> Result<String, Error> {
+        let len = self.len()?;
+        Ok(String::from_utf8(self.bytes(len)?.to_vec())?)
+    }
+
+    fn chrom(&mut self) -> Result<String, Error> {
+        let index = self.u64()? as usize;
+        self.chrom_names
+            .get(index)
+            .cloned()
+            .ok_or_else(|| Error::msg("invalid chromosome in annotation cache"))
+    }
+
+    fn intervals(&mut self) -> Result<Vec<(i64, i64)>, Error> {
+        let n = self.len()?;
+        (0..n).map(|_| Ok((self.i64()?, self.i64()?))).collect()
+    }
+
+    fn region(&mut self) -> Result<Region, Error> {
+        Ok(Region {
+            seqname: self.chrom()?,
+            start: self.i64()?,
+            end: self.i64()?,
+        })
+    }
+
+    fn regions(&mut self) -> Result<Vec<Region>, Error> {
+        let n = self.len()?;
+        (0..n).map(|_| self.region()).collect()
+    }
+
+    fn genes(&mut self) -> Result<Vec<Gene>, Error> {
+        let n = self.len()?;
+        (0..n)
+            .map(|index| {
+                let id = self.str()?;
+                let biotype = match self.u64()? {
+                    0 => None,
+                    _ => Some(self.str()?),
+                };
+                Ok(Gene {
+                    id,
+                    index,
+                    biotype,
+                    region: self.region()?,
+                    exons: self.intervals()?,
+                })
+            })
+            .collect()
+    }
+
+    fn transcripts(&mut self) -> Result<Vec<Transcript>, Error> {
+        let n = self.len()?;
+        (0..n)
+            .map(|_| {
+                Ok(Transcript {
+                    id: self.str()?,
+                    gene_id: self.str()?,
+                    seqname: self.chrom()?,
+                    exons: self.intervals()?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(seqname: &str, start: i64, end: i64) -> Region {
+        Region {
+            seqname: seqname.to_string(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let exons = vec![region("chr1", 100, 200), region("chr2", 50, 80)];
+        let genes = vec![Gene {
+            id: "gene1".to_string(),
+            index: 0,
+            biotype: Some("protein_coding".to_string()),
+            region: region("chr1", 100, 400),
+            exons: vec![(100, 200), (300, 400)],
+        }];
+        let transcripts = vec![Transcript {
+            id: "t1".to_string(),
+            gene_id: "gene1".to_string(),
+            seqname: "chr2".to_string(),
+            exons: vec![(50, 80)],
+        }];
+        let mut encoder = Encoder::new();
+        encoder.regions(&exons);
+        encoder.genes(&genes);
+        encoder.transcripts(&transcripts);
+        let data = encoder.finish();
+
+        let mut decoder = Decoder::new(&data).unwrap();
+        let decoded = decoder.regions().unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(
+            (
+                decoded[1].seqname.as_str(),
+                decoded[1].start,
+                decoded[1].end
+            ),
+            ("chr2", 50, 80)
+        );
+        let decoded = decoder.genes().unwrap();
+        assert_eq!(decoded[0].id, "gene1");
+        assert_eq!(decoded[0].biotype.as_deref(), Some("protein_coding"));
+        assert_eq!(decoded[0].region.end, 400);
+        assert_eq!(decoded[0].exons, genes[0].exons);
+        let decoded = decoder.transcripts().unwrap();
+        assert_eq!(
+            (decoded[0].id.as_str(), decoded[0].seqname.as_str()),
+            ("t1", "chr2")
+        );
+
+        // Truncated or foreign files are rejected
+        let mut decoder = Decoder::new(&data[..data.len() - 4]).unwrap();
+        decoder.regions().unwrap();
+        decoder.genes().unwrap();
+        assert!(decoder.transcripts().is_err());
+        assert!(Decoder::new(b"##gff-version 3\n").is_err());
+    }
+}
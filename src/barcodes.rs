@@ -0,0 +1,182 @@
+use crate::cigar::cigar_five_prime_pos;
+use crate::classify::RegionClassifier;
+use anyhow::Error;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::Record;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+// Cell barcode and UMI of a read, taken from its aux tags, and the
+// unclipped 5' position the UMI was seen at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CellTag {
+    pub barcode: Vec<u8>,
+    pub umi: Option<Vec<u8>>,
+    pub tid: i32,
+    pub pos: i64,
+}
+
+fn string_tag(read: &Record, tag: &[u8]) -> Option<Vec<u8>> {
+    match read.aux(tag) {
+        Ok(Aux::String(value)) => Some(value.as_bytes().to_vec()),
+        _ => None,
+    }
+}
+
+// Returns the cell tag of a read, or None if the read has no barcode or
+// its barcode is not in the whitelist.
+pub fn cell_tag(
+    read: &Record,
+    barcode_tag: &str,
+    umi_tag: &str,
+    whitelist: Option<&HashSet<Vec<u8>>>,
+) -> Option<CellTag> {
+    let barcode = string_tag(read, barcode_tag.as_bytes())?;
+    if let Some(whitelist) = whitelist {
+        if !whitelist.contains(&barcode) {
+            return None;
+        }
+    }
+    Some(CellTag {
+        barcode,
+        umi: string_tag(read, umi_tag.as_bytes()),
+        tid: read.tid(),
+        pos: cigar_five_prime_pos(read),
+    })
+}
+
+// A UMI at a chromosome and position. The same UMI at another position is
+// another molecule.
+type UmiKey = (Vec<u8>, i32, i64);
+
+// Accepted read counts for one cell barcode, in total and for each label of
+// the category classifier
+#[derive(Debug, Clone, Default)]
+pub struct BarcodeCounts {
+    pub mapped: usize,
+    pub categories: Vec<usize>,
+    pub umis: HashSet<UmiKey>,
+}
+
+impl BarcodeCounts {
+    pub fn add(&mut self, categories: &[usize], cell: &CellTag) {
+        self.mapped += 1;
+        for &category in categories {
+            if self.categories.len() <= category {
+                self.categories.resize(category + 1, 0);
+            }
+            self.categories[category] += 1;
+        }
+        if let Some(umi) = &cell.umi {
+            self.umis.insert((umi.clone(), cell.tid, cell.pos));
+        }
+    }
+
+    pub fn merge(&mut self, other: BarcodeCounts) {
+        self.mapped += other.mapped;
+        if self.categories.len() < other.categories.len() {
+            self.categories.resize(other.categories.len(), 0);
+        }
+        for (count, other_count) in self.categories.iter_mut().zip(other.categories) {
+            *count += other_count;
+        }
+        self.umis.extend(other.umis);
+    }
+
+    // Reads of the category with index `category`
+    pub fn category(&self, category: usize) -> usize {
+        self.categories.get(category).copied().unwrap_or(0)
+    }
+
+    pub fn exonic_fraction(&self) -> f64 {
+        if self.mapped == 0 {
+            0.0
+        } else {
+            self.category(RegionClassifier::EXON) as f64 / self.mapped as f64
+        }
+    }
+}
+
+pub fn merge_barcode_counts(
+    counts: &mut HashMap<Vec<u8>, BarcodeCounts>,
+    other: HashMap<Vec<u8>, BarcodeCounts>,
+) {
+    for (barcode, barcode_counts) in other {
+        counts.entry(barcode).or_default().merge(barcode_counts);
+    }
+}
+
+// Writes one row per barcode, ordered by decreasing number of mapped reads,
+// with a column for each of the category `labels`.
+pub fn write_barcode_table(
+    out: &mut impl Write,
+    labels: &[String],
+    counts: &HashMap<Vec<u8>, BarcodeCounts>,
+) -> Result<(), Error> {
+    let mut barcodes: Vec<_> = counts.iter().collect();
+    barcodes.sort_by(|a, b| b.1.mapped.cmp(&a.1.mapped).then_with(|| a.0.cmp(b.0)));
+    writeln!(
+        out,
+        "#Barcode\tMapped\t{}\tUMIs\tExonicFraction",
+        labels.join("\t")
+    )?;
+    for (barcode, c) in barcodes {
+        write!(out, "{}\t{}", String::from_utf8_lossy(barcode), c.mapped)?;
+        for category in 0..labels.len() {
+            write!(out, "\t{}", c.category(category))?;
+        }
+        writeln!(out, "\t{}\t{:.4}", c.umis.len(), c.exonic_fraction())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(umi: Option<&[u8]>, pos: i64) -> CellTag {
+        CellTag {
+            barcode: b"BARCODE".to_vec(),
+            umi: umi.map(|umi| umi.to_vec()),
+            tid: 0,
+            pos,
+        }
+    }
+
+    #[test]
+    fn test_barcode_counts_add_and_merge() {
+        let mut a = BarcodeCounts::default();
+        a.add(&[0], &cell(Some(b"AAAA"), 100));
+        a.add(&[1], &cell(Some(b"AAAA"), 100));
+        // The same UMI at another position is another molecule
+        a.add(&[0], &cell(Some(b"AAAA"), 500));
+        let mut b = BarcodeCounts::default();
+        b.add(&[0], &cell(Some(b"CCCC"), 100));
+        b.add(&[2], &cell(None, 100));
+        a.merge(b);
+        assert_eq!(a.mapped, 5);
+        assert_eq!(a.categories, vec![3, 1, 1]);
+        assert_eq!(a.umis.len(), 3);
+        assert_eq!(a.exonic_fraction(), 0.6);
+    }
+
+    #[test]
+    fn test_write_barcode_table_sorted_by_mapped() {
+        let mut counts: HashMap<Vec<u8>, BarcodeCounts> = HashMap::new();
+        counts
+            .entry(b"LOW".to_vec())
+            .or_default()
+            .add(&[0], &cell(None, 100));
+        let high = counts.entry(b"HIGH".to_vec()).or_default();
+        high.add(&[0], &cell(Some(b"A"), 100));
+        // A read without a category, e.g. intronic with only Exon counted
+        high.add(&[], &cell(Some(b"C"), 100));
+        let labels = vec!["Exon".to_string()];
+        let mut out = vec![];
+        write_barcode_table(&mut out, &labels, &counts).unwrap();
+        let lines: Vec<_> = std::str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(lines[0], "#Barcode\tMapped\tExon\tUMIs\tExonicFraction");
+        assert_eq!(lines[1], "HIGH\t2\t1\t2\t0.5000");
+        assert_eq!(lines[2], "LOW\t1\t1\t0\t1.0000");
+    }
+}
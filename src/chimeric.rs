@@ -0,0 +1,300 @@
+use crate::classify::IntervalSet;
+use crate::regions::Gene;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::Record;
+use serde::Serialize;
+use std::collections::HashMap;
+
+// One alignment of an SA tag: `rname,pos,strand,CIGAR,mapQ,NM;`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SupplementaryAlignment {
+    pub chrom: String,
+    // 0-based start and end on the reference
+    pub start: i64,
+    pub end: i64,
+    pub reverse: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChimeraKind {
+    // All parts on the same chromosome and strand, e.g. circular RNAs or
+    // deletions
+    Intrachromosomal,
+    // A part on another chromosome, e.g. translocations and fusions
+    Interchromosomal,
+    // A part on the same chromosome but the other strand, e.g. inversions
+    StrandSwitching,
+}
+
+// Parses the alignments of an SA tag, skipping malformed ones
+pub fn parse_sa_tag(tag: &str) -> Vec<SupplementaryAlignment> {
+    tag.split(';')
+        .filter_map(|alignment| {
+            let fields: Vec<&str> = alignment.split(',').collect();
+            if fields.len() < 4 {
+                return None;
+            }
+            let start = fields[1].parse::<i64>().ok()? - 1;
+            Some(SupplementaryAlignment {
+                chrom: fields[0].to_string(),
+                start,
+                end: start + cigar_reference_length(fields[3])?,
+                reverse: fields[2] == "-",
+            })
+        })
+        .collect()
+}
+
+// Reference bases covered by a CIGAR string
+fn cigar_reference_length(cigar: &str) -> Option<i64> {
+    let mut length = 0;
+    let mut number = String::new();
+    for c in cigar.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let len: i64 = std::mem::take(&mut number).parse().ok()?;
+        if matches!(c, 'M' | 'D' | 'N' | '=' | 'X') {
+            length += len;
+        }
+    }
+    Some(length)
+}
+
+// Another chromosome outranks a strand switch, which outranks neither
+pub fn classify_chimera(
+    chrom: &str,
+    reverse: bool,
+    alignments: &[SupplementaryAlignment],
+) -> ChimeraKind {
+    if alignments.iter().any(|a| a.chrom != chrom) {
+        ChimeraKind::Interchromosomal
+    } else if alignments.iter().any(|a| a.reverse != reverse) {
+        ChimeraKind::StrandSwitching
+    } else {
+        ChimeraKind::Intrachromosomal
+    }
+}
+
+// Gene spans of all chromosomes, for finding the genes at both ends of a
+// chimera
+#[derive(Debug, Clone)]
+pub struct GeneSpans {
+    ids: Vec<String>,
+    intervals: HashMap<String, IntervalSet>,
+}
+
+impl GeneSpans {
+    pub fn new(genes: &[Gene]) -> Self {
+        let mut by_chrom: HashMap<String, Vec<(i64, i64, usize)>> = HashMap::new();
+        for (i, gene) in genes.iter().enumerate() {
+            by_chrom
+                .entry(gene.region.seqname.clone())
+                .or_default()
+                .push((gene.region.start, gene.region.end, i));
+        }
+        GeneSpans {
+            ids: genes.iter().map(|gene| gene.id.clone()).collect(),
+            intervals: by_chrom
+                .into_iter()
+                .map(|(chrom, intervals)| (chrom, IntervalSet::new(intervals)))
+                .collect(),
+        }
+    }
+
+    fn genes(&self, chrom: &str, blocks: &[(i64, i64)]) -> Vec<usize> {
+        let mut genes = vec![];
+        if let Some(intervals) = self.intervals.get(chrom) {
+            intervals.overlapping(blocks, &mut genes);
+            genes.sort_unstable();
+            genes.dedup();
+        }
+        genes
+    }
+}
+
+// Accepted primary reads with an SA tag, by kind, and by the pair of
+// distinct genes joined
+#[derive(Debug, Clone, Default)]
+pub struct ChimericCounts {
+    intrachromosomal: usize,
+    interchromosomal: usize,
+    strand_switching: usize,
+    gene_pairs: HashMap<(usize, usize), usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GenePair {
+    pub gene1: String,
+    pub gene2: String,
+    pub reads: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChimericReport {
+    pub reads: usize,
+    // Chimeric reads over accepted mapped reads
+    pub fraction: f64,
+    pub intrachromosomal: usize,
+    pub interchromosomal: usize,
+    pub strand_switching: usize,
+    // Ordered by decreasing number of reads
+    pub gene_pairs: Vec<GenePair>,
+}
+
+impl ChimericCounts {
+    // Adds a primary read on `chrom` aligned in `blocks` if it has an SA tag
+    pub fn add(&mut self, chrom: &str, read: &Record, blocks: &[(i64, i64)], genes: &GeneSpans) {
+        let Ok(Aux::String(tag)) = read.aux(b"SA") else {
+            return;
+        };
+        let alignments = parse_sa_tag(tag);
+        if alignments.is_empty() {
+            return;
+        }
+        match classify_chimera(chrom, read.is_reverse(), &alignments) {
+            ChimeraKind::Intrachromosomal => self.intrachromosomal += 1,
+            ChimeraKind::Interchromosomal => self.interchromosomal += 1,
+            ChimeraKind::StrandSwitching => self.strand_switching += 1,
+        }
+        let primary_genes = genes.genes(chrom, blocks);
+        let mut pairs = vec![];
+        for alignment in &alignments {
+            for other in genes.genes(&alignment.chrom, &[(alignment.start, alignment.end)]) {
+                for &gene in &primary_genes {
+                    if gene != other {
+                        pairs.push((gene.min(other), gene.max(other)));
+                    }
+                }
+            }
+        }
+        pairs.sort_unstable();
+        pairs.dedup();
+        for pair in pairs {
+            *self.gene_pairs.entry(pair).or_insert(0) += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &ChimericCounts) {
+        self.intrachromosomal += other.intrachromosomal;
+        self.interchromosomal += other.interchromosomal;
+        self.strand_switching += other.strand_switching;
+        for (pair, reads) in &other.gene_pairs {
+            *self.gene_pairs.entry(*pair).or_insert(0) += reads;
+        }
+    }
+
+    pub fn report(&self, genes: &GeneSpans, mapped_reads: usize) -> ChimericReport {
+        let reads = self.intrachromosomal + self.interchromosomal + self.strand_switching;
+        let mut gene_pairs: Vec<GenePair> = self
+            .gene_pairs
+            .iter()
+            .map(|(&(gene1, gene2), &reads)| GenePair {
+                gene1: genes.ids[gene1].clone(),
+                gene2: genes.ids[gene2].clone(),
+                reads,
+            })
+            .collect();
+        gene_pairs.sort_by(|a, b| {
+            b.reads
+                .cmp(&a.reads)
+                .then_with(|| (&a.gene1, &a.gene2).cmp(&(&b.gene1, &b.gene2)))
+        });
+        ChimericReport {
+            reads,
+            fraction: if mapped_reads > 0 {
+                reads as f64 / mapped_reads as f64
+            } else {
+                0.0
+            },
+            intrachromosomal: self.intrachromosomal,
+            interchromosomal: self.interchromosomal,
+            strand_switching: self.strand_switching,
+            gene_pairs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Region;
+
+    #[test]
+    fn test_parse_sa_tag() {
+        let alignments = parse_sa_tag("chr2,1001,-,30S20M5D50M,60,2;chr1,500,+,50M50S,0,0;bad;");
+        assert_eq!(
+            alignments,
+            vec![
+                SupplementaryAlignment {
+                    chrom: "chr2".to_string(),
+                    start: 1000,
+                    end: 1075,
+                    reverse: true,
+                },
+                SupplementaryAlignment {
+                    chrom: "chr1".to_string(),
+                    start: 499,
+                    end: 549,
+                    reverse: false,
+                },
+            ]
+        );
+        assert_eq!(
+            classify_chimera("chr1", false, &alignments),
+            ChimeraKind::Interchromosomal
+        );
+        assert_eq!(
+            classify_chimera("chr1", true, &alignments[1..]),
+            ChimeraKind::StrandSwitching
+        );
+        assert_eq!(
+            classify_chimera("chr1", false, &alignments[1..]),
+            ChimeraKind::Intrachromosomal
+        );
+    }
+
+    #[test]
+    fn test_chimeric_gene_pairs() {
+        let gene = |index: usize, seqname: &str, start, end| Gene {
+            id: format!("gene{}", index),
+            index,
+            biotype: None,
+            region: Region {
+                seqname: seqname.to_string(),
+                start,
+                end,
+            },
+            exons: vec![(start, end)],
+        };
+        let genes = GeneSpans::new(&[
+            gene(0, "chr1", 0, 1000),
+            gene(1, "chr2", 900, 2000),
+            gene(2, "chr2", 5000, 6000),
+        ]);
+        let mut read = Record::new();
+        read.push_aux(b"SA", Aux::String("chr2,1001,-,50M,60,0;"))
+            .unwrap();
+        let mut counts = ChimericCounts::default();
+        counts.add("chr1", &read, &[(100, 150)], &genes);
+        counts.add("chr1", &Record::new(), &[(100, 150)], &genes);
+        let mut other = ChimericCounts::default();
+        other.add("chr1", &read, &[(200, 250)], &genes);
+        counts.merge(&other);
+
+        let report = counts.report(&genes, 20);
+        assert_eq!(report.reads, 2);
+        assert_eq!(report.interchromosomal, 2);
+        assert_eq!(report.fraction, 0.1);
+        assert_eq!(report.gene_pairs.len(), 1);
+        assert_eq!(
+            (
+                report.gene_pairs[0].gene1.as_str(),
+                report.gene_pairs[0].gene2.as_str(),
+                report.gene_pairs[0].reads
+            ),
+            ("gene0", "gene1", 2)
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::reference::open_bam;
+use crate::{CategoryCounts, CountResult};
+use anyhow::Error;
+use rust_htslib::bam::Read;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+// Counts of the mapped reads on one contig
+#[derive(Debug, Clone, Serialize)]
+pub struct ChromosomeReport {
+    pub name: String,
+    pub length: u64,
+    #[serde(flatten)]
+    pub categories: CategoryCounts,
+    pub mapped: CountResult,
+    // Accepted mapped reads per megabase of contig, to compare contigs of
+    // different sizes
+    pub reads_per_mb: Option<f64>,
+}
+
+// Names and lengths of the contigs in the BAM header, in header order, so
+// that the index of a contig is its tid
+pub fn chromosome_lengths(
+    bamfile: &Path,
+    reference: Option<&Path>,
+) -> Result<Vec<(String, u64)>, Error> {
+    let bam = open_bam(bamfile, reference)?;
+    let header = bam.header();
+    header
+        .target_names()
+        .iter()
+        .enumerate()
+        .map(|(tid, name)| {
+            let name = String::from_utf8(name.to_vec())
+                .map_err(|e| Error::msg(format!("Error reading chromosome names: {}", e)))?;
+            Ok((name, header.target_len(tid as u32).unwrap_or(0)))
+        })
+        .collect()
+}
+
+// One report per counted contig, in header order. Counted contigs without
+// reads are kept, as a missing chromosome is as telling as an extra one.
+// `labels` are the categories of the counts.
+pub fn chromosome_reports(
+    lengths: &[(String, u64)],
+    counted: &HashSet<&str>,
+    counts: &BTreeMap<i32, CategoryCounts>,
+    labels: &[String],
+) -> Vec<ChromosomeReport> {
+    lengths
+        .iter()
+        .enumerate()
+        .filter(|(_, (name, _))| counted.contains(name.as_str()))
+        .map(|(tid, (name, length))| {
+            let categories = counts
+                .get(&(tid as i32))
+                .cloned()
+                .unwrap_or_else(|| CategoryCounts::new(labels));
+            let mapped = categories.mapped();
+            let reads_per_mb = (*length > 0).then(|| mapped.accepted as f64 * 1e6 / *length as f64);
+            ChromosomeReport {
+                name: name.clone(),
+                length: *length,
+                categories,
+                mapped,
+                reads_per_mb,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReadCheckOutcome;
+
+    #[test]
+    fn test_chromosome_reports() {
+        let lengths = vec![
+            ("chr1".to_string(), 2_000_000),
+            ("chrY".to_string(), 500_000),
+            ("chrM".to_string(), 16_569),
+        ];
+        let labels = vec!["Exon".to_string()];
+        let mut chr1 = CategoryCounts::new(&labels);
+        chr1.add(&[0], &ReadCheckOutcome::Accept);
+        chr1.add(&[], &ReadCheckOutcome::Accept);
+        chr1.add(&[0], &ReadCheckOutcome::Reject);
+        let counts = BTreeMap::from([(0, chr1)]);
+        let counted = HashSet::from(["chr1", "chrY"]);
+
+        let reports = chromosome_reports(&lengths, &counted, &counts, &labels);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].name, "chr1");
+        assert_eq!(reports[0].categories.get("Exon").unwrap().accepted, 1);
+        assert_eq!(
+            (reports[0].mapped.accepted, reports[0].mapped.rejected),
+            (2, 1)
+        );
+        assert_eq!(reports[0].reads_per_mb, Some(1.0));
+        // Counted contigs without reads are reported with zero counts
+        assert_eq!(reports[1].name, "chrY");
+        assert_eq!(reports[1].mapped.accepted, 0);
+        assert_eq!(reports[1].categories.labels(), labels);
+        assert_eq!(reports[1].reads_per_mb, Some(0.0));
+    }
+}
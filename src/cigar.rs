@@ -14,6 +14,88 @@ pub(crate) fn cigar_end_pos(record: &Record) -> i64 {
     pos
 }
 
+// Reference position of the unclipped 5' end of the read: the start of a
+// forward read or the end of a reverse read, extended by any soft clipping.
+pub(crate) fn cigar_five_prime_pos(record: &Record) -> i64 {
+    let cigar = record.cigar();
+    if record.is_reverse() {
+        cigar_end_pos(record) + cigar.trailing_softclips()
+    } else {
+        record.pos() - cigar.leading_softclips()
+    }
+}
+
+// Reference intervals covered by alignment matches, as 0-based, half-open
+// [start, end) pairs in increasing order. Walking the CIGAR once and reusing
+// the blocks is much cheaper than calling check_cigar_overlap per region
+// for long reads with many operations.
+pub(crate) fn cigar_blocks(record: &Record) -> Vec<(i64, i64)> {
+    let mut blocks = vec![];
+    let mut pos = record.pos();
+    for cigar in record.cigar().iter() {
+        let len = cigar.len() as i64;
+        match cigar.char() {
+            'M' | '=' | 'X' => {
+                blocks.push((pos, pos + len));
+                pos += len;
+            }
+            'D' | 'N' => pos += len,
+            _ => {}
+        }
+    }
+    blocks
+}
+
+// Reference intervals of the read between splice junctions: like
+// cigar_blocks, but deletions do not split a segment, only 'N' does.
+pub(crate) fn cigar_spliced_segments(record: &Record) -> Vec<(i64, i64)> {
+    let mut segments = vec![];
+    let mut pos = record.pos();
+    let mut start = pos;
+    for cigar in record.cigar().iter() {
+        let len = cigar.len() as i64;
+        match cigar.char() {
+            'M' | '=' | 'X' | 'D' => pos += len,
+            'N' => {
+                if pos > start {
+                    segments.push((start, pos));
+                }
+                pos += len;
+                start = pos;
+            }
+            _ => {}
+        }
+    }
+    if pos > start {
+        segments.push((start, pos));
+    }
+    segments
+}
+
+// Number of read bases aligned to the reference (matches and insertions)
+pub(crate) fn cigar_aligned_length(record: &Record) -> i64 {
+    record
+        .cigar()
+        .iter()
+        .map(|cigar| match cigar.char() {
+            'M' | '=' | 'X' | 'I' => cigar.len() as i64,
+            _ => 0,
+        })
+        .sum()
+}
+
+// Total number of soft-clipped bases at either end of the read
+pub(crate) fn cigar_soft_clip_length(record: &Record) -> i64 {
+    record
+        .cigar()
+        .iter()
+        .map(|cigar| match cigar.char() {
+            'S' => cigar.len() as i64,
+            _ => 0,
+        })
+        .sum()
+}
+
 // Function to check if there's an overlap between the read (based on CIGAR) and an interval
 // The interval is 0-based, half-open, i.e. [start, end)
 pub(crate) fn check_cigar_overlap(record: &Record, interval_start: i64, interval_end: i64) -> bool {
@@ -39,6 +121,7 @@ pub(crate) fn check_cigar_overlap(record: &Record, interval_start: i64, interval
 }
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_cast)]
 mod tests {
     use super::*;
     use rust_htslib::{
@@ -107,6 +190,58 @@ mod tests {
         assert_eq!(cigar_end_pos(&record), 61_845_205); // End position of the match
     }
 
+    #[test]
+    fn test_cigar_five_prime_pos() {
+        let mut record = mock_record(
+            vec![('S', 4), ('M', 50), ('N', 100), ('M', 20), ('S', 6)],
+            100,
+        );
+        assert_eq!(cigar_five_prime_pos(&record), 96); // Forward: start minus leading clip
+        record.set_flags(16);
+        assert_eq!(cigar_five_prime_pos(&record), 276); // Reverse: end plus trailing clip
+    }
+
+    #[test]
+    fn test_cigar_blocks() {
+        let record = mock_record(
+            vec![
+                ('S', 4),
+                ('M', 10),
+                ('I', 2),
+                ('M', 5),
+                ('D', 3),
+                ('M', 5),
+                ('N', 100),
+                ('M', 20),
+            ],
+            100,
+        );
+        assert_eq!(
+            cigar_blocks(&record),
+            vec![(100, 110), (110, 115), (118, 123), (223, 243)]
+        );
+        // Only the intron splits the spliced segments
+        assert_eq!(
+            cigar_spliced_segments(&record),
+            vec![(100, 123), (223, 243)]
+        );
+    }
+
+    #[test]
+    fn test_cigar_aligned_length() {
+        let record = mock_record(
+            vec![('S', 4), ('M', 45), ('I', 2), ('N', 100), ('M', 26)],
+            100,
+        );
+        assert_eq!(cigar_aligned_length(&record), 73); // Matches and insertions only
+    }
+
+    #[test]
+    fn test_cigar_soft_clip_length() {
+        let record = mock_record(vec![('S', 4), ('M', 45), ('S', 3)], 100);
+        assert_eq!(cigar_soft_clip_length(&record), 7);
+    }
+
     #[test]
     fn test_overlap_full_match() {
         let record = mock_record(vec![('M', 50)], 100); // 50 matches from position 100
@@ -0,0 +1,515 @@
+use crate::cigar::{check_cigar_overlap, cigar_end_pos};
+use crate::io::read_bed_named_regions;
+use crate::regions::{overlap_length, ChromosomeRegions, Gene, Region};
+use crate::CountResult;
+use anyhow::Error;
+use rust_htslib::bam::Record;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::path::Path;
+
+// Assigns mapped reads to zero or more labels. Each classifier gets its own
+// table in the report, with the accepted and rejected reads of each label.
+// Classifiers are shared between the threads counting each chromosome, so
+// `classify` may be called for reads of different chromosomes concurrently,
+// but reads of one chromosome are always visited in order of position.
+pub trait ReadClassifier: Debug + Send + Sync {
+    // Title of the classifier's table in the report
+    fn name(&self) -> &str;
+
+    // All labels the classifier can assign
+    fn labels(&self) -> &[String];
+
+    // Adds the indices (into `labels`) of the labels of a read on `chrom`,
+    // whose aligned blocks are `blocks`, to `classes`
+    fn classify(&self, chrom: &str, read: &Record, blocks: &[(i64, i64)], classes: &mut Vec<usize>);
+}
+
+// Read counts of each label of one classifier
+#[derive(Debug, Clone, Serialize)]
+pub struct Classification {
+    pub name: String,
+    pub labels: Vec<LabelCounts>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LabelCounts {
+    pub label: String,
+    #[serde(flatten)]
+    pub counts: CountResult,
+}
+
+impl Classification {
+    pub fn new(classifier: &dyn ReadClassifier, counts: &[CountResult]) -> Self {
+        Classification {
+            name: classifier.name().to_string(),
+            labels: classifier
+                .labels()
+                .iter()
+                .zip(counts)
+                .map(|(label, counts)| LabelCounts {
+                    label: label.clone(),
+                    counts: counts.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+// Labelled intervals on one chromosome, sorted by start and laid out as an
+// implicit interval tree: the interval at index i is a node of level k if i
+// has k trailing ones, and `max_end[i]` is the largest end in its subtree. A
+// search skips every subtree that ends before the query, so one long
+// interval does not make each query scan the whole chromosome.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IntervalSet {
+    intervals: Vec<(i64, i64, usize)>,
+    max_end: Vec<i64>,
+    max_level: usize,
+}
+
+impl IntervalSet {
+    pub(crate) fn new(mut intervals: Vec<(i64, i64, usize)>) -> Self {
+        intervals.sort();
+        let n = intervals.len();
+        let mut max_end: Vec<i64> = intervals.iter().map(|&(_, end, _)| end).collect();
+        if n == 0 {
+            return IntervalSet::default();
+        }
+        // The last leaf and the largest end below it, standing in for the
+        // missing right children of the nodes past the end
+        let mut last_i = (n - 1) & !1;
+        let mut last = max_end[last_i];
+        let mut k = 1;
+        while 1 << k <= n {
+            let x = 1 << (k - 1);
+            for i in ((2 * x - 1)..n).step_by(4 * x) {
+                let right = if i + x < n { max_end[i + x] } else { last };
+                max_end[i] = max_end[i].max(max_end[i - x]).max(right);
+            }
+            last_i = if (last_i >> k) & 1 == 1 {
+                last_i - x
+            } else {
+                last_i + x
+            };
+            if last_i < n {
+                last = last.max(max_end[last_i]);
+            }
+            k += 1;
+        }
+        IntervalSet {
+            intervals,
+            max_end,
+            max_level: k - 1,
+        }
+    }
+
+    pub(crate) fn overlapping(&self, blocks: &[(i64, i64)], labels: &mut Vec<usize>) {
+        let n = self.intervals.len();
+        if n == 0 {
+            return;
+        }
+        for &(start, end) in blocks {
+            // Nodes to visit, and whether their left subtree has been visited
+            let mut stack = vec![((1 << self.max_level) - 1, self.max_level, false)];
+            while let Some((x, k, left_done)) = stack.pop() {
+                if k <= 3 {
+                    // Small subtrees are scanned in order
+                    let first = (x >> k) << k;
+                    let last = (first + (1 << (k + 1)) - 1).min(n);
+                    for &(s, e, label) in self.intervals[first.min(n)..last].iter() {
+                        if s >= end {
+                            break;
+                        }
+                        if e > start {
+                            labels.push(label);
+                        }
+                    }
+                } else if !left_done {
+                    let left = x - (1 << (k - 1));
+                    stack.push((x, k, true));
+                    if left >= n || self.max_end[left] > start {
+                        stack.push((left, k - 1, false));
+                    }
+                } else if x < n && self.intervals[x].0 < end {
+                    let (_, e, label) = self.intervals[x];
+                    if e > start {
+                        labels.push(label);
+                    }
+                    stack.push((x + (1 << (k - 1)), k - 1, false));
+                }
+            }
+        }
+    }
+}
+
+// Labels reads by the named regions they overlap: the exons of each gene,
+// the exons of genes of each biotype, or the regions of a BED file. A read
+// gets each label at most once.
+#[derive(Debug, Clone)]
+pub struct FeatureClassifier {
+    name: String,
+    labels: Vec<String>,
+    intervals: HashMap<String, IntervalSet>,
+}
+
+impl FeatureClassifier {
+    // `features` are regions and indices into `labels`
+    pub fn new(name: &str, labels: Vec<String>, features: Vec<(Region, usize)>) -> Self {
+        let mut by_chrom: HashMap<String, Vec<(i64, i64, usize)>> = HashMap::new();
+        for (region, label) in features {
+            by_chrom
+                .entry(region.seqname)
+                .or_default()
+                .push((region.start, region.end, label));
+        }
+        FeatureClassifier {
+            name: name.to_string(),
+            labels,
+            intervals: by_chrom
+                .into_iter()
+                .map(|(chrom, intervals)| (chrom, IntervalSet::new(intervals)))
+                .collect(),
+        }
+    }
+
+    // One label per gene, for reads overlapping the gene's exons. Genes are
+    // labelled by gene_id, in annotation order.
+    pub fn by_gene(genes: &[Gene]) -> Self {
+        let labels = genes.iter().map(|gene| gene.id.clone()).collect();
+        let features = genes
+            .iter()
+            .enumerate()
+            .flat_map(|(label, gene)| gene_exon_regions(gene).map(move |r| (r, label)))
+            .collect();
+        FeatureClassifier::new("Gene", labels, features)
+    }
+
+    // One label per gene biotype, for reads overlapping the exons of a gene
+    // of that biotype. Genes without a biotype are left out.
+    pub fn by_biotype(genes: &[Gene]) -> Self {
+        let labels: Vec<String> = genes
+            .iter()
+            .filter_map(|gene| gene.biotype.clone())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let index: HashMap<&str, usize> = labels
+            .iter()
+            .enumerate()
+            .map(|(i, label)| (label.as_str(), i))
+            .collect();
+        let features = genes
+            .iter()
+            .filter_map(|gene| Some((gene, index[gene.biotype.as_deref()?])))
+            .flat_map(|(gene, label)| gene_exon_regions(gene).map(move |r| (r, label)))
+            .collect();
+        FeatureClassifier::new("Biotype", labels, features)
+    }
+
+    // One label per region name in a BED file, or a single label named
+    // after the file for regions without a name
+    pub fn from_bed(path: &Path) -> Result<Self, Error> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut labels = vec![];
+        let mut index: HashMap<String, usize> = HashMap::new();
+        let mut features = vec![];
+        for (region, label) in read_bed_named_regions(path)? {
+            let label = label.unwrap_or_else(|| name.clone());
+            let i = *index.entry(label.clone()).or_insert_with(|| {
+                labels.push(label);
+                labels.len() - 1
+            });
+            features.push((region, i));
+        }
+        Ok(FeatureClassifier::new(&name, labels, features))
+    }
+}
+
+fn gene_exon_regions(gene: &Gene) -> impl Iterator<Item = Region> + '_ {
+    gene.exons.iter().map(|&(start, end)| Region {
+        seqname: gene.region.seqname.clone(),
+        start,
+        end,
+    })
+}
+
+impl ReadClassifier for FeatureClassifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    fn classify(
+        &self,
+        chrom: &str,
+        _read: &Record,
+        blocks: &[(i64, i64)],
+        classes: &mut Vec<usize>,
+    ) {
+        if let Some(intervals) = self.intervals.get(chrom) {
+            let mut found = vec![];
+            intervals.overlapping(blocks, &mut found);
+            found.sort_unstable();
+            found.dedup();
+            classes.extend(found);
+        }
+    }
+}
+
+// Where a mapped read falls relative to the annotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RegionCategory {
+    Exon,
+    Intron,
+    Intergenic,
+}
+
+// The categories of the main report: reads overlapping the compressed exon
+// regions with an alignment match are exonic, other reads overlapping a gene
+// region intronic and the rest intergenic. Only Exon is a label unless intron
+// and intergenic reads are counted; other reads then get no label.
+#[derive(Debug, Clone)]
+pub struct RegionClassifier {
+    labels: Vec<String>,
+    regions: HashMap<String, ChromosomeRegions>,
+}
+
+impl RegionClassifier {
+    // Index of the Exon label, which is always the first
+    pub const EXON: usize = 0;
+
+    pub fn new(regions: HashMap<String, ChromosomeRegions>, intron_intergenic: bool) -> Self {
+        let labels: &[&str] = if intron_intergenic {
+            &["Exon", "Intron", "Intergenic"]
+        } else {
+            &["Exon"]
+        };
+        RegionClassifier {
+            labels: labels.iter().map(|label| label.to_string()).collect(),
+            regions,
+        }
+    }
+
+    // Compressed exon and gene regions of each chromosome that is counted
+    pub fn chromosomes(&self) -> &HashMap<String, ChromosomeRegions> {
+        &self.regions
+    }
+
+    pub fn category(&self, chrom: &str, read: &Record, blocks: &[(i64, i64)]) -> RegionCategory {
+        let Some(regions) = self.regions.get(chrom) else {
+            return RegionCategory::Intergenic;
+        };
+        let first = regions
+            .exons
+            .partition_point(|region| region.end <= read.pos());
+        let end_pos = cigar_end_pos(read);
+        if regions.exons[first..]
+            .iter()
+            .take_while(|region| region.start <= end_pos)
+            .any(|region| check_cigar_overlap(read, region.start, region.end))
+        {
+            RegionCategory::Exon
+        } else if overlap_length(blocks, &regions.genes) > 0 {
+            RegionCategory::Intron
+        } else {
+            RegionCategory::Intergenic
+        }
+    }
+
+    // Index of the label of a category, if it is one of the labels
+    pub fn label(&self, category: RegionCategory) -> Option<usize> {
+        let label = match category {
+            RegionCategory::Exon => RegionClassifier::EXON,
+            RegionCategory::Intron => 1,
+            RegionCategory::Intergenic => 2,
+        };
+        (label < self.labels.len()).then_some(label)
+    }
+}
+
+impl ReadClassifier for RegionClassifier {
+    fn name(&self) -> &str {
+        "Category"
+    }
+
+    fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    fn classify(
+        &self,
+        chrom: &str,
+        read: &Record,
+        blocks: &[(i64, i64)],
+        classes: &mut Vec<usize>,
+    ) {
+        classes.extend(self.label(self.category(chrom, read, blocks)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cigar::cigar_blocks;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    fn region(start: i64, end: i64) -> Region {
+        Region {
+            seqname: "chr1".to_string(),
+            start,
+            end,
+        }
+    }
+
+    fn classes(classifier: &dyn ReadClassifier, blocks: &[(i64, i64)]) -> Vec<usize> {
+        let mut classes = vec![];
+        classifier.classify("chr1", &Record::new(), blocks, &mut classes);
+        classes
+    }
+
+    #[test]
+    fn test_interval_set_matches_linear_scan() {
+        // A chromosome-wide interval among many short ones, of every set size
+        // around the tree's level boundaries
+        for n in [0, 1, 2, 7, 8, 9, 31, 100, 257] {
+            let mut intervals = vec![(0, 1_000_000, 0)];
+            let mut seed = 17u64;
+            for label in 1..n {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let start = (seed >> 33) as i64 % 10_000;
+                intervals.push((start, start + 1 + (seed >> 20) as i64 % 300, label));
+            }
+            let set = IntervalSet::new(intervals.clone());
+            for start in (0..10_500).step_by(37) {
+                let blocks = [(start, start + 50), (start + 400, start + 410)];
+                let mut found = vec![];
+                set.overlapping(&blocks, &mut found);
+                found.sort_unstable();
+                let mut expected: Vec<_> = blocks
+                    .iter()
+                    .flat_map(|&(start, end)| {
+                        intervals
+                            .iter()
+                            .filter(move |&&(s, e, _)| s < end && e > start)
+                            .map(|&(_, _, label)| label)
+                    })
+                    .collect();
+                expected.sort_unstable();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_feature_classifier() {
+        let labels = vec!["long".to_string(), "a".to_string(), "b".to_string()];
+        let classifier = FeatureClassifier::new(
+            "Test",
+            labels,
+            vec![
+                (region(0, 10_000), 0),
+                (region(100, 200), 1),
+                (region(300, 400), 1),
+                (region(500, 600), 2),
+            ],
+        );
+        assert_eq!(classes(&classifier, &[(150, 160)]), vec![0, 1]);
+        // Two exons of the same label count once
+        assert_eq!(classes(&classifier, &[(150, 160), (350, 360)]), vec![0, 1]);
+        assert_eq!(classes(&classifier, &[(450, 550)]), vec![0, 2]);
+        assert_eq!(
+            classes(&classifier, &[(20_000, 20_100)]),
+            Vec::<usize>::new()
+        );
+        let mut other_chrom = vec![];
+        classifier.classify("chr2", &Record::new(), &[(150, 160)], &mut other_chrom);
+        assert!(other_chrom.is_empty());
+    }
+
+    #[test]
+    fn test_feature_classifier_by_biotype() {
+        let gene = |index, biotype: Option<&str>, exons: Vec<(i64, i64)>| Gene {
+            id: format!("gene{}", index),
+            index,
+            biotype: biotype.map(|b| b.to_string()),
+            region: region(exons[0].0, exons.last().unwrap().1),
+            exons,
+        };
+        let genes = vec![
+            gene(0, Some("protein_coding"), vec![(100, 200), (300, 400)]),
+            gene(1, Some("lncRNA"), vec![(1000, 1100)]),
+            gene(2, None, vec![(2000, 2100)]),
+        ];
+        let by_biotype = FeatureClassifier::by_biotype(&genes);
+        assert_eq!(by_biotype.labels(), ["lncRNA", "protein_coding"]);
+        assert_eq!(classes(&by_biotype, &[(350, 360)]), vec![1]);
+        assert_eq!(classes(&by_biotype, &[(2050, 2060)]), Vec::<usize>::new());
+        let by_gene = FeatureClassifier::by_gene(&genes);
+        assert_eq!(by_gene.labels().len(), 3);
+        // Intronic reads do not count for the gene
+        assert_eq!(classes(&by_gene, &[(250, 260)]), Vec::<usize>::new());
+        assert_eq!(classes(&by_gene, &[(2050, 2060)]), vec![2]);
+    }
+
+    #[test]
+    fn test_region_classifier() {
+        let mut regions = HashMap::new();
+        regions.insert(
+            "chr1".to_string(),
+            ChromosomeRegions {
+                exons: vec![region(100, 200)],
+                genes: vec![region(100, 500)],
+                gene_spans: vec![],
+            },
+        );
+        let classifier = RegionClassifier::new(regions.clone(), true);
+        let exon_only = RegionClassifier::new(regions, false);
+        let read = |pos, cigar: &[Cigar]| {
+            let mut read = Record::new();
+            let length = cigar.iter().map(|op| op.len()).sum::<u32>() as usize;
+            read.set(
+                b"read",
+                Some(&CigarString(cigar.to_vec())),
+                &vec![b'A'; length],
+                &vec![30; length],
+            );
+            read.set_pos(pos);
+            let mut classes = vec![];
+            classifier.classify("chr1", &read, &cigar_blocks(&read), &mut classes);
+            // Without intron and intergenic labels only exonic reads get one
+            let mut exon_classes = vec![];
+            exon_only.classify("chr1", &read, &cigar_blocks(&read), &mut exon_classes);
+            assert_eq!(
+                exon_classes,
+                classes
+                    .iter()
+                    .copied()
+                    .filter(|&c| c == 0)
+                    .collect::<Vec<_>>()
+            );
+            classes
+        };
+        assert_eq!(read(150, &[Cigar::Match(10)]), vec![0]);
+        assert_eq!(read(300, &[Cigar::Match(10)]), vec![1]);
+        // Spliced over the exon, or only soft clipped into it
+        assert_eq!(
+            read(
+                50,
+                &[Cigar::Match(10), Cigar::RefSkip(200), Cigar::Match(10)]
+            ),
+            vec![1]
+        );
+        assert_eq!(read(200, &[Cigar::SoftClip(20), Cigar::Match(10)]), vec![1]);
+        assert_eq!(read(600, &[Cigar::Match(10)]), vec![2]);
+    }
+}
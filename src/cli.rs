@@ -1,5 +1,9 @@
 use clap::error::ErrorKind;
-use clap::{CommandFactory, Parser};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use region_counter::extract::ExtractCategory;
+use region_counter::library::{detect_library_layout, LibraryLayout};
+use region_counter::{Counter, Expression, Preset, ReadFilter, UmiMethod};
 use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug, Clone)]
@@ -8,9 +12,21 @@ pub struct ProgramOptions {
     #[arg(short, long)]
     pub bamfile: PathBuf,
 
+    /// GTF annotation, plain or compressed with gzip, bgzip, bzip2 or xz, or
+    /// - to read it from standard input
     #[arg(short = 'g', long)]
     pub gtf: PathBuf,
 
+    /// Reference FASTA for CRAM input, checked against the contig lengths and
+    /// MD5s (M5) of the header
+    #[arg(long, value_name = "FASTA")]
+    pub reference: Option<PathBuf>,
+
+    /// Directory in which to cache the parsed annotation, keyed by the
+    /// content of the GTF file, so that later runs against it skip parsing
+    #[arg(long, value_name = "DIR")]
+    pub annotation_cache: Option<PathBuf>,
+
     #[arg(short = 'q', long, default_value = "35")]
     pub minmapqual: u8,
 
@@ -19,6 +35,404 @@ pub struct ProgramOptions {
 
     #[arg(short = 'F', long, default_value = "2816")]
     pub filtered_flag: u16,
+
+    /// Also reject mapped reads for which this expression is false, e.g.
+    /// '[NM] <= 5 && !has_tag(SA)'
+    #[arg(short = 'e', long, value_parser = Expression::parse)]
+    pub filter: Option<Expression>,
+
+    /// Set mapping quality and flag filters for a library type. Explicitly
+    /// given -q, -f and -F take precedence. If no preset is given, paired or
+    /// single-end is detected from the first reads of the BAM file
+    #[arg(short = 'p', long, value_enum)]
+    pub preset: Option<Preset>,
+
+    #[arg(skip)]
+    pub preset_detected: bool,
+
+    #[arg(skip)]
+    pub library_layout: Option<LibraryLayout>,
+
+    /// In long-read mode, only count a read as exonic if at least this
+    /// fraction of its aligned bases fall in exons
+    #[arg(long)]
+    pub min_exon_fraction: Option<f64>,
+
+    /// Also count mapped reads outside exons as Intron, if they overlap a
+    /// gene, or Intergenic
+    #[arg(long)]
+    pub intron_intergenic: bool,
+
+    /// Write a per-cell-barcode table of accepted read counts to this file
+    #[arg(long)]
+    pub cell_table: Option<PathBuf>,
+
+    /// Aux tag holding the cell barcode
+    #[arg(long, default_value = "CB")]
+    pub cell_barcode_tag: String,
+
+    /// Aux tag holding the UMI, used to count UMIs per cell barcode
+    #[arg(long, default_value = "UB")]
+    pub cell_umi_tag: String,
+
+    /// Only report cell barcodes listed in this file (one per line, optionally gzipped)
+    #[arg(long, requires = "cell_table")]
+    pub cell_whitelist: Option<PathBuf>,
+
+    /// Deduplicate reads by position, strand and the UMI in this aux tag
+    #[arg(long, conflicts_with = "umi_separator")]
+    pub umi_tag: Option<String>,
+
+    /// Deduplicate reads by position, strand and the UMI at the end of the
+    /// read name, after the last occurrence of this character
+    #[arg(long)]
+    pub umi_separator: Option<char>,
+
+    /// How UMIs at the same position are grouped into molecules
+    #[arg(long, value_enum, default_value_t = UmiMethod::Exact)]
+    pub umi_method: UmiMethod,
+
+    /// Estimate duplicates from reads with identical fragment start, end and
+    /// strand, for BAM files without duplicate flags
+    #[arg(long)]
+    pub estimate_duplicates: bool,
+
+    /// Report template length, read length, soft-clip length and mapping
+    /// quality distributions of mapped reads
+    #[arg(short = 'd', long)]
+    pub distributions: bool,
+
+    /// Report exonic reads, detected genes and distinct fragments at
+    /// subsampled depths, and extrapolate distinct fragments to higher depths
+    #[arg(long)]
+    pub saturation: bool,
+
+    /// Report counts for each read group (RG tag) in the BAM header, as
+    /// well as in total
+    #[arg(long)]
+    pub by_read_group: bool,
+
+    /// Report category and mapped counts for each contig, with its length and
+    /// accepted reads per megabase
+    #[arg(long)]
+    pub by_chromosome: bool,
+
+    /// Report the reads overlapping the exons of each gene
+    #[arg(long)]
+    pub by_gene: bool,
+
+    /// Write a table of the reads, FPKM and TPM of each gene to this file.
+    /// Implies --by-gene
+    #[arg(long)]
+    pub gene_table: Option<PathBuf>,
+
+    /// Mean fragment length for the effective gene lengths of --gene-table.
+    /// Defaults to the mean template length of accepted reads
+    #[arg(long, value_name = "LENGTH")]
+    pub fragment_length: Option<f64>,
+
+    /// Write the accepted fragments of each transcript equivalence class
+    /// (the set of transcripts the exon structure of both mates is
+    /// compatible with) to this file, in the eq_classes.txt layout of Salmon
+    #[arg(long, value_name = "FILE")]
+    pub equivalence_classes: Option<PathBuf>,
+
+    /// Write the spliced, boundary-spanning and intronic reads and the
+    /// retention ratio of each annotated intron to this file
+    #[arg(long, value_name = "FILE")]
+    pub intron_table: Option<PathBuf>,
+
+    /// Write the mean and median depth of accepted reads, and the fraction
+    /// of bases covered at each --coverage-threshold, of each merged exon
+    /// region to this file
+    #[arg(long, value_name = "FILE")]
+    pub coverage_table: Option<PathBuf>,
+
+    /// Depths at which --coverage-table reports the fraction of bases
+    /// covered. Can be given more than once or as a comma-separated list
+    #[arg(
+        long = "coverage-threshold",
+        value_name = "DEPTH",
+        value_delimiter = ',',
+        default_value = "1,10,30"
+    )]
+    pub coverage_thresholds: Vec<u32>,
+
+    /// Write the depth of accepted reads over the merged exon regions to
+    /// this bedGraph file
+    #[arg(long, value_name = "FILE")]
+    pub bedgraph: Option<PathBuf>,
+
+    /// Report accepted primary reads with supplementary alignments (SA tag)
+    /// as intra-chromosomal, inter-chromosomal or strand-switching chimeras,
+    /// and the pairs of genes they join
+    #[arg(long)]
+    pub chimeric: bool,
+
+    /// Write every processed read to this BAM file, with its assignment
+    /// (Assigned or Unassigned_<reason>) in the XS tag and its genes in the
+    /// XT tag, as featureCounts -R BAM. XS and XT tags of other types from
+    /// the aligner, such as the XS:A strand tag of STAR and HISAT2, are kept
+    /// and the read is then not tagged
+    #[arg(long, value_name = "BAM")]
+    pub output_bam: Option<PathBuf>,
+
+    /// Write the reads of these categories to --extract-output. Can be given
+    /// more than once or as a comma-separated list
+    #[arg(
+        long,
+        value_name = "CATEGORY",
+        value_delimiter = ',',
+        requires = "extract_output"
+    )]
+    pub extract: Vec<ExtractCategory>,
+
+    /// File for the --extract reads: FASTQ if its name ends in .fastq or .fq
+    /// (optionally .gz), otherwise BAM
+    #[arg(long, value_name = "FILE", requires = "extract")]
+    pub extract_output: Option<PathBuf>,
+
+    /// Report the reads overlapping the exons of genes of each biotype
+    /// (gene_biotype or gene_type attribute)
+    #[arg(long)]
+    pub by_biotype: bool,
+
+    /// Report the reads overlapping each named region of this BED file. Can
+    /// be given more than once
+    #[arg(long = "bed-set", value_name = "BED")]
+    pub bed_sets: Vec<PathBuf>,
+
+    /// Count the reads on this contig as a spike-in, apart from the mapped
+    /// reads. Can be given more than once or as a comma-separated list
+    #[arg(long = "spike-in-contig", value_name = "CONTIG", value_delimiter = ',')]
+    pub spike_in_contigs: Vec<String>,
+
+    /// Count the reads overlapping the exons of each gene of this GTF file
+    /// as a spike-in, apart from the mapped reads
+    #[arg(long)]
+    pub spike_in_gtf: Option<PathBuf>,
+
+    /// Fit spike-in counts against the concentrations in this table of
+    /// spike-in names and concentrations
+    #[arg(long, value_name = "TSV")]
+    pub spike_in_concentrations: Option<PathBuf>,
+
+    /// Only count reads overlapping this region, given as chr, chr:start or
+    /// chr:start-end (1-based, inclusive). Can be given more than once
+    #[arg(long = "region", value_name = "REGION")]
+    pub regions: Vec<String>,
+
+    /// Only count reads overlapping the regions in this BED file
+    #[arg(long)]
+    pub targets: Option<PathBuf>,
+
+    /// Do not count unmapped reads
+    #[arg(long)]
+    pub skip_unmapped: bool,
+
+    /// Only count this fraction of templates, chosen by a hash of the read
+    /// name so that both mates are kept or dropped together
+    #[arg(long, conflicts_with = "subsample_reads")]
+    pub subsample_fraction: Option<f64>,
+
+    /// Only count about this many reads, by subsampling templates as for
+    /// --subsample-fraction
+    #[arg(long)]
+    pub subsample_reads: Option<u64>,
+
+    /// Seed for the read name hash used to subsample templates
+    #[arg(long, default_value = "0")]
+    pub subsample_seed: u64,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Tsv)]
+    pub format: OutputFormat,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Tsv,
+    Json,
+}
+
+fn preset_for_layout(layout: LibraryLayout) -> Preset {
+    match layout {
+        LibraryLayout::Paired => Preset::Paired,
+        LibraryLayout::Single => Preset::Single,
+    }
+}
+
+impl ProgramOptions {
+    pub fn long_read_mode(&self) -> bool {
+        self.preset == Some(Preset::LongRead)
+    }
+
+    // Builds a counter with the options given on the command line
+    pub fn counter(&self) -> Counter {
+        let mut counter = Counter::new(&self.bamfile, &self.gtf);
+        if let Some(preset) = self.preset {
+            counter = counter.preset(preset, self.preset_detected);
+        }
+        if let Some(layout) = self.library_layout {
+            counter = counter.library_layout(layout);
+        }
+        if let Some(reference) = &self.reference {
+            counter = counter.reference(reference);
+        }
+        if let Some(dir) = &self.annotation_cache {
+            counter = counter.annotation_cache(dir);
+        }
+        counter = counter
+            .filter(ReadFilter {
+                minmapqual: self.minmapqual,
+                required_flag: self.required_flag,
+                filtered_flag: self.filtered_flag,
+            })
+            .intron_intergenic(self.intron_intergenic)
+            .estimate_duplicates(self.estimate_duplicates)
+            .distributions(self.distributions)
+            .saturation(self.saturation)
+            .skip_unmapped(self.skip_unmapped)
+            .by_gene(self.by_gene)
+            .gene_expression(self.gene_table.is_some())
+            .equivalence_classes(self.equivalence_classes.is_some())
+            .intron_retention(self.intron_table.is_some())
+            .chimeric(self.chimeric)
+            .by_biotype(self.by_biotype)
+            .by_read_group(self.by_read_group)
+            .by_chromosome(self.by_chromosome);
+        if let Some(expression) = &self.filter {
+            counter = counter.expression(expression.clone());
+        }
+        if let Some(fraction) = self.min_exon_fraction {
+            counter = counter.min_exon_fraction(fraction);
+        }
+        if self.cell_table.is_some() {
+            counter = counter.cell_barcodes(
+                &self.cell_barcode_tag,
+                &self.cell_umi_tag,
+                self.cell_whitelist.clone(),
+            );
+        }
+        if let Some(tag) = &self.umi_tag {
+            counter = counter.umi_tag(tag, self.umi_method);
+        }
+        if let Some(separator) = self.umi_separator {
+            counter = counter.umi_separator(separator, self.umi_method);
+        }
+        if let Some(fraction) = self.subsample_fraction {
+            counter = counter.subsample_fraction(fraction, self.subsample_seed);
+        }
+        if let Some(reads) = self.subsample_reads {
+            counter = counter.subsample_reads(reads, self.subsample_seed);
+        }
+        for bedfile in &self.bed_sets {
+            counter = counter.bed_set(bedfile);
+        }
+        if let Some(path) = &self.extract_output {
+            counter = counter.extract(&self.extract, path);
+        }
+        if let Some(path) = &self.output_bam {
+            counter = counter.output_bam(path);
+        }
+        if let Some(length) = self.fragment_length {
+            counter = counter.fragment_length(length);
+        }
+        if self.coverage_table.is_some() {
+            counter = counter.coverage(&self.coverage_thresholds);
+        }
+        if let Some(path) = &self.bedgraph {
+            counter = counter.bedgraph(path);
+        }
+        for contig in &self.spike_in_contigs {
+            counter = counter.spike_in_contig(contig);
+        }
+        if let Some(gtf) = &self.spike_in_gtf {
+            counter = counter.spike_in_gtf(gtf);
+        }
+        if let Some(table) = &self.spike_in_concentrations {
+            counter = counter.spike_in_concentrations(table);
+        }
+        for region in &self.regions {
+            counter = counter.region(region);
+        }
+        if let Some(targets) = &self.targets {
+            counter = counter.targets(targets);
+        }
+        counter
+    }
+}
+
+fn is_default(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::DefaultValue)
+}
+
+// Fills in any filter options the user did not give explicitly from the
+// chosen preset, or from the detected library layout if there is no preset.
+// The layout is also detected for --gene-table, which counts the fragments
+// of paired reads once.
+fn apply_preset(args: &mut ProgramOptions, matches: &ArgMatches) {
+    let choose_preset = args.preset.is_none() && is_default(matches, "required_flag");
+    if choose_preset || (args.preset.is_none() && args.gene_table.is_some()) {
+        match detect_library_layout(&args.bamfile, args.reference.as_deref()) {
+            Ok(Some(layout)) => {
+                args.library_layout = Some(layout);
+                if choose_preset {
+                    args.preset = Some(preset_for_layout(layout));
+                    args.preset_detected = true;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Warning: could not detect library layout: {}", e),
+        }
+    }
+    if let Some(preset) = args.preset {
+        let filter = preset.filter();
+        if is_default(matches, "minmapqual") {
+            args.minmapqual = filter.minmapqual;
+        }
+        if is_default(matches, "required_flag") {
+            args.required_flag = filter.required_flag;
+        }
+        if is_default(matches, "filtered_flag") {
+            args.filtered_flag = filter.filtered_flag;
+        }
+    }
+}
+
+fn validate_min_exon_fraction(args: &ProgramOptions) {
+    if let Some(fraction) = args.min_exon_fraction {
+        let mut cmd = ProgramOptions::command();
+        if !args.long_read_mode() {
+            cmd.error(
+                ErrorKind::ArgumentConflict,
+                "--min-exon-fraction requires --preset long-read",
+            )
+            .exit();
+        }
+        if !(0.0..=1.0).contains(&fraction) {
+            cmd.error(
+                ErrorKind::ValueValidation,
+                format!("--min-exon-fraction `{}` is not between 0 and 1", fraction),
+            )
+            .exit();
+        }
+    }
+}
+
+fn validate_subsample_fraction(args: &ProgramOptions) {
+    if let Some(fraction) = args.subsample_fraction {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            let mut cmd = ProgramOptions::command();
+            cmd.error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "--subsample-fraction `{}` is not greater than 0 and at most 1",
+                    fraction
+                ),
+            )
+            .exit();
+        }
+    }
 }
 
 fn validate_file(file: &Path) {
@@ -33,8 +447,33 @@ fn validate_file(file: &Path) {
 }
 
 pub fn parse_cli() -> ProgramOptions {
-    let args = ProgramOptions::parse();
+    let matches = ProgramOptions::command().get_matches();
+    let mut args = ProgramOptions::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     validate_file(&args.bamfile);
-    validate_file(&args.gtf);
+    // The annotation can be read from standard input
+    if args.gtf != Path::new("-") {
+        validate_file(&args.gtf);
+    }
+    if let Some(reference) = &args.reference {
+        validate_file(reference);
+    }
+    if let Some(whitelist) = &args.cell_whitelist {
+        validate_file(whitelist);
+    }
+    if let Some(targets) = &args.targets {
+        validate_file(targets);
+    }
+    for bedfile in &args.bed_sets {
+        validate_file(bedfile);
+    }
+    if let Some(gtf) = &args.spike_in_gtf {
+        validate_file(gtf);
+    }
+    if let Some(table) = &args.spike_in_concentrations {
+        validate_file(table);
+    }
+    apply_preset(&mut args, &matches);
+    validate_min_exon_fraction(&args);
+    validate_subsample_fraction(&args);
     args
 }
@@ -0,0 +1,1840 @@
+use crate::annotate::{
+    concatenate_parts, filter_assignment, Assignment, PartWriter, PrimaryAssignments,
+};
+use crate::annotation_cache::AnnotationCache;
+use crate::barcodes::{cell_tag, merge_barcode_counts, BarcodeCounts, CellTag};
+use crate::chimeric::{ChimericCounts, GeneSpans};
+use crate::chromosomes::{chromosome_lengths, chromosome_reports};
+use crate::cigar::{cigar_blocks, cigar_spliced_segments};
+use crate::classify::{Classification, FeatureClassifier, ReadClassifier, RegionClassifier};
+use crate::coverage::{
+    concatenate_bedgraph_parts, create_bedgraph_part, CoverageAccumulator, CoverageTable,
+    RegionCoverage,
+};
+use crate::dedup::{add_molecules, read_umi, Molecule, UmiGroups};
+use crate::distributions::DistributionSet;
+use crate::duplicates::{FragmentKey, FragmentTracker};
+use crate::expression::Expression;
+use crate::extract::{is_extracted, split_read_parts, write_extracted, ExtractCategory};
+use crate::introns::{IntronCounts, IntronIndex, IntronTable};
+use crate::io;
+use crate::library::{detect_library_layout, LibraryLayout};
+use crate::long_read::{AlignedBases, SplitRead};
+use crate::normalise::gene_expression;
+use crate::read_groups::{read_group_reports, ReadGroups};
+use crate::reference::{check_reference, open_bam, open_indexed_bam};
+use crate::regions::{
+    advance_past, group_targets, index_regions, overlap_length, parse_region,
+    sort_regions_in_place, GeneCursor, Region,
+};
+use crate::report::{CountReport, ReportOptions};
+use crate::sampling::{fraction_for_reads, read_name_fraction, Subsample};
+use crate::saturation::{subsample_bin, SaturationCounts, SaturationRead};
+use crate::spike_ins::SpikeIns;
+use crate::transcripts::{EquivalenceClassTable, FragmentClasses, ReadClass, TranscriptIndex};
+use anyhow::Error;
+use clap::ValueEnum;
+use rayon::prelude::*;
+use rust_htslib::bam::{IndexedReader, Read, Record};
+use serde::ser::SerializeMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const FLAGS_ALWAYS_FILTERED: u16 = 2816;
+pub(crate) const FLAG_PROPER_PAIR: u16 = 2;
+const FLAG_UNMAPPED: u16 = 4;
+const FLAG_DUPLICATE: u16 = 1024;
+const FLAG_SUPPLEMENTARY: u16 = 2048;
+const FLAGS_MAPPING_RELATED: u16 = 63;
+// Distance behind the current read after which UMI groups and fragment
+// signatures are complete. Must exceed the longest soft clip at the start of
+// a forward read and the distance between the mates of most pairs.
+const FLUSH_WINDOW: i64 = 100_000;
+// Seed for the read name hash that assigns reads to saturation curve bins.
+// Differs from the default subsampling seed so that the bins of a subsample
+// are still evenly filled.
+const SATURATION_SEED: u64 = 0x5a7;
+// Index in `Counter::classifiers` of the classifier whose labels are the
+// categories of the main table
+const CATEGORY_CLASSIFIER: usize = 0;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ReadCheckOutcome {
+    Accept,
+    Reject,
+}
+
+// Mapping quality and flag filters deciding whether a read is accepted
+#[derive(Debug, Clone, Copy)]
+pub struct ReadFilter {
+    pub minmapqual: u8,
+    pub required_flag: u16,
+    pub filtered_flag: u16,
+}
+
+impl Default for ReadFilter {
+    fn default() -> Self {
+        ReadFilter {
+            minmapqual: 35,
+            required_flag: 3,
+            filtered_flag: 2816,
+        }
+    }
+}
+
+impl ReadFilter {
+    pub fn check(&self, read: &Record) -> ReadCheckOutcome {
+        if read.mapq() < self.minmapqual {
+            return ReadCheckOutcome::Reject;
+        }
+
+        if read.flags() & self.required_flag != self.required_flag
+            || read.flags() & self.filtered_flag != 0
+        {
+            return ReadCheckOutcome::Reject;
+        }
+
+        ReadCheckOutcome::Accept
+    }
+}
+
+#[derive(ValueEnum, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UmiMethod {
+    /// Each distinct UMI is a molecule
+    Exact,
+    /// UMIs connected by single mismatches are one molecule
+    Cluster,
+    /// As cluster, but a UMI only absorbs UMIs with at most about half its count
+    Directional,
+}
+
+#[derive(ValueEnum, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    Paired,
+    Single,
+    LongRead,
+}
+
+impl Preset {
+    pub fn filter(&self) -> ReadFilter {
+        let (minmapqual, required_flag, filtered_flag) = match self {
+            Preset::Paired => (35, 3, 2816),
+            Preset::Single => (35, 0, 2816),
+            Preset::LongRead => (20, 0, 2816),
+        };
+        ReadFilter {
+            minmapqual,
+            required_flag,
+            filtered_flag,
+        }
+    }
+}
+
+// Counts the reads of an indexed BAM file by category of the annotation in
+// a GTF file. Options are set with the builder methods, e.g.
+//
+//     let report = Counter::new("sample.bam", "genes.gtf.gz")
+//         .preset(Preset::Single)
+//         .estimate_duplicates(true)
+//         .run()?;
+#[derive(Debug, Clone)]
+pub struct Counter {
+    bamfile: PathBuf,
+    gtf: PathBuf,
+    reference: Option<PathBuf>,
+    annotation_cache: Option<PathBuf>,
+    filter: ReadFilter,
+    expression: Option<Expression>,
+    preset: Option<Preset>,
+    preset_detected: bool,
+    library_layout: Option<LibraryLayout>,
+    min_exon_fraction: Option<f64>,
+    intron_intergenic: bool,
+    cell_barcodes: bool,
+    cell_barcode_tag: String,
+    cell_umi_tag: String,
+    cell_whitelist: Option<PathBuf>,
+    umi_tag: Option<String>,
+    umi_separator: Option<char>,
+    umi_method: UmiMethod,
+    estimate_duplicates: bool,
+    distributions: bool,
+    saturation: bool,
+    subsample_fraction: Option<f64>,
+    subsample_reads: Option<u64>,
+    subsample_seed: u64,
+    regions: Vec<String>,
+    targets: Option<PathBuf>,
+    skip_unmapped: bool,
+    by_gene: bool,
+    by_biotype: bool,
+    bed_sets: Vec<PathBuf>,
+    classifiers: Vec<Arc<dyn ReadClassifier>>,
+    by_read_group: bool,
+    read_groups: Option<ReadGroups>,
+    by_chromosome: bool,
+    gene_expression: bool,
+    fragment_length: Option<f64>,
+    equivalence_classes: bool,
+    transcripts: Option<Arc<TranscriptIndex>>,
+    intron_retention: bool,
+    introns: Option<Arc<IntronIndex>>,
+    chimeric: bool,
+    gene_spans: Option<Arc<GeneSpans>>,
+    coverage: bool,
+    coverage_thresholds: Vec<u32>,
+    bedgraph: Option<PathBuf>,
+    output_bam: Option<PathBuf>,
+    extract: Vec<ExtractCategory>,
+    extract_output: Option<PathBuf>,
+    assignment_genes: Option<Arc<FeatureClassifier>>,
+    spike_in_contigs: Vec<String>,
+    spike_in_gtf: Option<PathBuf>,
+    spike_in_concentrations: Option<PathBuf>,
+    spike_ins: Option<SpikeIns>,
+}
+
+impl Counter {
+    pub fn new(bamfile: impl Into<PathBuf>, gtf: impl Into<PathBuf>) -> Self {
+        Counter {
+            bamfile: bamfile.into(),
+            gtf: gtf.into(),
+            reference: None,
+            annotation_cache: None,
+            filter: ReadFilter::default(),
+            expression: None,
+            preset: None,
+            preset_detected: false,
+            library_layout: None,
+            min_exon_fraction: None,
+            intron_intergenic: false,
+            cell_barcodes: false,
+            cell_barcode_tag: "CB".to_string(),
+            cell_umi_tag: "UB".to_string(),
+            cell_whitelist: None,
+            umi_tag: None,
+            umi_separator: None,
+            umi_method: UmiMethod::Exact,
+            estimate_duplicates: false,
+            distributions: false,
+            saturation: false,
+            subsample_fraction: None,
+            subsample_reads: None,
+            subsample_seed: 0,
+            regions: vec![],
+            targets: None,
+            skip_unmapped: false,
+            by_gene: false,
+            by_biotype: false,
+            bed_sets: vec![],
+            classifiers: vec![],
+            by_read_group: false,
+            read_groups: None,
+            by_chromosome: false,
+            gene_expression: false,
+            fragment_length: None,
+            equivalence_classes: false,
+            transcripts: None,
+            intron_retention: false,
+            introns: None,
+            chimeric: false,
+            gene_spans: None,
+            coverage: false,
+            coverage_thresholds: vec![1, 10, 30],
+            bedgraph: None,
+            output_bam: None,
+            extract: vec![],
+            extract_output: None,
+            assignment_genes: None,
+            spike_in_contigs: vec![],
+            spike_in_gtf: None,
+            spike_in_concentrations: None,
+            spike_ins: None,
+        }
+    }
+
+    // Reference FASTA for decoding CRAM files, checked against the contig
+    // lengths and MD5s of the header
+    pub fn reference(mut self, fasta: impl Into<PathBuf>) -> Self {
+        self.reference = Some(fasta.into());
+        self
+    }
+
+    // Loads the parsed annotation from a cache in this directory, or parses
+    // it and stores it there for later runs
+    pub fn annotation_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.annotation_cache = Some(dir.into());
+        self
+    }
+
+    pub fn filter(mut self, filter: ReadFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // Also rejects mapped reads that pass the filter but not the expression
+    pub fn expression(mut self, expression: Expression) -> Self {
+        self.expression = Some(expression);
+        self
+    }
+
+    // Sets the filters of the preset; filters set afterwards take precedence.
+    // `detected` records that the preset was chosen from the library layout.
+    pub fn preset(mut self, preset: Preset, detected: bool) -> Self {
+        self.filter = preset.filter();
+        self.preset = Some(preset);
+        self.preset_detected = detected;
+        self
+    }
+
+    // Paired or single-end, if already known, so that gene expression need
+    // not scan the start of the BAM file again
+    pub fn library_layout(mut self, layout: LibraryLayout) -> Self {
+        self.library_layout = Some(layout);
+        self
+    }
+
+    pub fn min_exon_fraction(mut self, fraction: f64) -> Self {
+        self.min_exon_fraction = Some(fraction);
+        self
+    }
+
+    // Collects accepted read counts per cell barcode, optionally only for
+    // the barcodes in a whitelist file
+    pub fn cell_barcodes(
+        mut self,
+        barcode_tag: &str,
+        umi_tag: &str,
+        whitelist: Option<PathBuf>,
+    ) -> Self {
+        self.cell_barcodes = true;
+        self.cell_barcode_tag = barcode_tag.to_string();
+        self.cell_umi_tag = umi_tag.to_string();
+        self.cell_whitelist = whitelist;
+        self
+    }
+
+    // Deduplicates reads by the UMI in an aux tag
+    pub fn umi_tag(mut self, tag: &str, method: UmiMethod) -> Self {
+        self.umi_tag = Some(tag.to_string());
+        self.umi_separator = None;
+        self.umi_method = method;
+        self
+    }
+
+    // Deduplicates reads by the UMI at the end of the read name
+    pub fn umi_separator(mut self, separator: char, method: UmiMethod) -> Self {
+        self.umi_separator = Some(separator);
+        self.umi_tag = None;
+        self.umi_method = method;
+        self
+    }
+
+    pub fn estimate_duplicates(mut self, estimate: bool) -> Self {
+        self.estimate_duplicates = estimate;
+        self
+    }
+
+    pub fn distributions(mut self, distributions: bool) -> Self {
+        self.distributions = distributions;
+        self
+    }
+
+    pub fn saturation(mut self, saturation: bool) -> Self {
+        self.saturation = saturation;
+        self
+    }
+
+    pub fn subsample_fraction(mut self, fraction: f64, seed: u64) -> Self {
+        self.subsample_fraction = Some(fraction);
+        self.subsample_reads = None;
+        self.subsample_seed = seed;
+        self
+    }
+
+    pub fn subsample_reads(mut self, reads: u64, seed: u64) -> Self {
+        self.subsample_reads = Some(reads);
+        self.subsample_fraction = None;
+        self.subsample_seed = seed;
+        self
+    }
+
+    // Restricts counting to a region given as chr, chr:start or chr:start-end
+    pub fn region(mut self, region: &str) -> Self {
+        self.regions.push(region.to_string());
+        self
+    }
+
+    // Restricts counting to the regions of a BED file
+    pub fn targets(mut self, bedfile: impl Into<PathBuf>) -> Self {
+        self.targets = Some(bedfile.into());
+        self
+    }
+
+    pub fn skip_unmapped(mut self, skip: bool) -> Self {
+        self.skip_unmapped = skip;
+        self
+    }
+
+    // Counts reads overlapping the exons of each gene
+    pub fn by_gene(mut self, by_gene: bool) -> Self {
+        self.by_gene = by_gene;
+        self
+    }
+
+    // Computes FPKM and TPM of each gene from the reads overlapping its
+    // exons. Implies `by_gene`.
+    pub fn gene_expression(mut self, gene_expression: bool) -> Self {
+        self.gene_expression = gene_expression;
+        self
+    }
+
+    // Mean fragment length used for the effective gene lengths. Without it,
+    // the mean template length of the accepted reads is used.
+    pub fn fragment_length(mut self, fragment_length: f64) -> Self {
+        self.fragment_length = Some(fragment_length);
+        self
+    }
+
+    // Counts accepted reads by the set of transcripts their aligned
+    // segments and splice junctions are compatible with
+    pub fn equivalence_classes(mut self, equivalence_classes: bool) -> Self {
+        self.equivalence_classes = equivalence_classes;
+        self
+    }
+
+    // Counts the spliced, boundary-spanning and intronic reads of each
+    // annotated intron
+    pub fn intron_retention(mut self, intron_retention: bool) -> Self {
+        self.intron_retention = intron_retention;
+        self
+    }
+
+    // Counts accepted primary reads with supplementary alignments (SA tag)
+    // by kind of chimera and by the pair of genes they join
+    pub fn chimeric(mut self, chimeric: bool) -> Self {
+        self.chimeric = chimeric;
+        self
+    }
+
+    // Summarises the depth of accepted reads over each merged exon region:
+    // mean, median and the fraction of bases at or above each threshold
+    pub fn coverage(mut self, thresholds: &[u32]) -> Self {
+        self.coverage = true;
+        self.coverage_thresholds = thresholds.to_vec();
+        self
+    }
+
+    // Writes the depth of accepted reads over the merged exon regions to a
+    // bedGraph file
+    pub fn bedgraph(mut self, path: impl Into<PathBuf>) -> Self {
+        self.bedgraph = Some(path.into());
+        self
+    }
+
+    // Writes every processed read to a BAM file, with what happened to it in
+    // the XS tag and the genes or spike-in it is assigned to in the XT tag
+    pub fn output_bam(mut self, path: impl Into<PathBuf>) -> Self {
+        self.output_bam = Some(path.into());
+        self
+    }
+
+    // Writes the reads of the given categories to a BAM file, or to a FASTQ
+    // file if its name ends in .fastq or .fq (optionally .gz)
+    pub fn extract(mut self, categories: &[ExtractCategory], path: impl Into<PathBuf>) -> Self {
+        self.extract = categories.to_vec();
+        self.extract_output = Some(path.into());
+        self
+    }
+
+    // Also counts mapped reads outside exons as Intron, if they overlap a
+    // gene, or Intergenic
+    pub fn intron_intergenic(mut self, intron_intergenic: bool) -> Self {
+        self.intron_intergenic = intron_intergenic;
+        self
+    }
+
+    // Counts reads overlapping the exons of genes of each biotype
+    pub fn by_biotype(mut self, by_biotype: bool) -> Self {
+        self.by_biotype = by_biotype;
+        self
+    }
+
+    // Counts reads overlapping each named region of a BED file
+    pub fn bed_set(mut self, bedfile: impl Into<PathBuf>) -> Self {
+        self.bed_sets.push(bedfile.into());
+        self
+    }
+
+    // Keeps separate counts for each read group in the BAM header
+    pub fn by_read_group(mut self, by_read_group: bool) -> Self {
+        self.by_read_group = by_read_group;
+        self
+    }
+
+    // Keeps separate counts for each contig, with the contig length and
+    // accepted reads per megabase
+    pub fn by_chromosome(mut self, by_chromosome: bool) -> Self {
+        self.by_chromosome = by_chromosome;
+        self
+    }
+
+    // Counts the reads on a contig as a spike-in instead of as mapped
+    pub fn spike_in_contig(mut self, contig: &str) -> Self {
+        self.spike_in_contigs.push(contig.to_string());
+        self
+    }
+
+    // Counts the reads overlapping the exons of each gene of a GTF file as
+    // a spike-in instead of as mapped
+    pub fn spike_in_gtf(mut self, gtf: impl Into<PathBuf>) -> Self {
+        self.spike_in_gtf = Some(gtf.into());
+        self
+    }
+
+    // Fits spike-in counts against the concentrations in a table of names
+    // and concentrations
+    pub fn spike_in_concentrations(mut self, table: impl Into<PathBuf>) -> Self {
+        self.spike_in_concentrations = Some(table.into());
+        self
+    }
+
+    // Adds a table of the reads assigned to each label of a classifier. The
+    // categories of the main table come from a built-in classifier that is
+    // always first.
+    pub fn classifier(mut self, classifier: Arc<dyn ReadClassifier>) -> Self {
+        self.classifiers.push(classifier);
+        self
+    }
+
+    // Long-read mode scores reads by their aligned bases in exons and
+    // attributes supplementary alignments to the primary read
+    pub fn long_read_mode(&self) -> bool {
+        self.preset == Some(Preset::LongRead)
+    }
+
+    fn deduplicate(&self) -> bool {
+        self.umi_tag.is_some() || self.umi_separator.is_some()
+    }
+
+    fn subsample(&self) -> Option<Subsample> {
+        self.subsample_fraction.map(|fraction| Subsample {
+            fraction,
+            seed: self.subsample_seed,
+        })
+    }
+
+    pub fn run(&self) -> Result<CountReport, Error> {
+        let mut args = self.clone();
+        if let Some(reads) = args.subsample_reads {
+            args.subsample_fraction = Some(fraction_for_reads(
+                &args.bamfile,
+                args.reference.as_deref(),
+                reads,
+            )?);
+        }
+        if let Some(reference) = &args.reference {
+            eprintln!("Checking reference: {}", reference.display());
+            check_reference(&args.bamfile, reference)?;
+        }
+        let targets = read_targets(&args)?;
+        let mut gtf = io::GtfFile::new(&args.gtf);
+        if let Some(targets) = &targets {
+            let mut regions: Vec<Region> = targets.values().flatten().cloned().collect();
+            sort_regions_in_place(&mut regions);
+            gtf = gtf.restrict_to(regions);
+        }
+        eprintln!("Reading GTF file: {}", args.gtf.display());
+        if gtf.tabix_regions()?.is_some() {
+            eprintln!("Only reading GTF records overlapping the targets, using its tabix index");
+        }
+        let cache = match &args.annotation_cache {
+            Some(dir) => Some(AnnotationCache::open(dir, &gtf)?),
+            None => None,
+        };
+        let (exons, genes) = match &cache {
+            Some(cache) => cache.exon_and_gene_regions(&gtf)?,
+            None => gtf.exon_and_gene_regions()?,
+        };
+        let mut regions_map = index_regions(&exons, &genes);
+        let chroms = get_chrom_names(&args.bamfile, args.reference.as_deref())?;
+        for chrom in chroms {
+            regions_map.entry(chrom).or_default();
+        }
+        if let Some(targets) = &targets {
+            regions_map.retain(|chrom, _| targets.contains_key(chrom));
+            for (chrom, regions) in regions_map.iter_mut() {
+                regions.restrict_to(&targets[chrom]);
+            }
+            eprintln!(
+                "Restricting counts to {} target regions",
+                targets.values().map(|t| t.len()).sum::<usize>()
+            );
+        }
+        let regions = Arc::new(RegionClassifier::new(regions_map, args.intron_intergenic));
+        // The categories of the main table come first
+        let mut classifiers: Vec<Arc<dyn ReadClassifier>> = vec![regions.clone()];
+        args.by_gene |= args.gene_expression;
+        // Index of the gene classifier, whose counts give gene expression
+        let mut gene_classifier = None;
+        if args.by_gene {
+            gene_classifier = Some(classifiers.len());
+            classifiers.push(Arc::new(FeatureClassifier::by_gene(&genes)));
+        }
+        if args.by_biotype {
+            classifiers.push(Arc::new(FeatureClassifier::by_biotype(&genes)));
+        }
+        for bedfile in &args.bed_sets {
+            classifiers.push(Arc::new(FeatureClassifier::from_bed(bedfile)?));
+        }
+        classifiers.append(&mut args.classifiers);
+        args.classifiers = classifiers;
+        if !args.spike_in_contigs.is_empty() || args.spike_in_gtf.is_some() {
+            args.spike_ins = Some(SpikeIns::new(
+                &args.spike_in_contigs,
+                args.spike_in_gtf.as_deref(),
+            )?);
+        }
+        if args.output_bam.is_some() {
+            args.assignment_genes = Some(Arc::new(FeatureClassifier::by_gene(&genes)));
+        }
+        if args.chimeric {
+            args.gene_spans = Some(Arc::new(GeneSpans::new(&genes)));
+        }
+        if args.equivalence_classes || args.intron_retention {
+            let transcripts = match &cache {
+                Some(cache) => cache.transcripts(&gtf)?,
+                None => gtf.transcripts()?,
+            };
+            eprintln!("Read {} transcripts", transcripts.len());
+            if args.intron_retention {
+                args.introns = Some(Arc::new(IntronIndex::new(&transcripts)));
+            }
+            if args.equivalence_classes {
+                args.transcripts = Some(Arc::new(TranscriptIndex::new(transcripts)));
+            }
+        }
+        if args.by_read_group {
+            args.read_groups = Some(ReadGroups::from_bam(
+                &args.bamfile,
+                args.reference.as_deref(),
+            )?);
+        }
+        if let Some(preset) = args.preset {
+            eprintln!(
+                "Using {:?} preset{}: minmapqual={}, required flag={}, filtered flag={}",
+                preset,
+                if args.preset_detected {
+                    " (detected)"
+                } else {
+                    ""
+                },
+                args.filter.minmapqual,
+                args.filter.required_flag,
+                args.filter.filtered_flag
+            );
+        }
+        let chromosomes = regions.chromosomes();
+        let n_regions = chromosomes.values().map(|v| v.exons.len()).sum::<usize>();
+        let n_genes = chromosomes.values().map(|v| v.genes.len()).sum::<usize>();
+        eprintln!(
+            "Counting {} exon regions and {} gene regions on {} chromosomes",
+            n_regions,
+            n_genes,
+            chromosomes.len()
+        );
+        if let Some(subsample) = args.subsample() {
+            eprintln!(
+                "Subsampling {:.4}% of templates (seed {})",
+                subsample.fraction * 100.0,
+                subsample.seed
+            );
+        }
+        let whitelist = match &args.cell_whitelist {
+            Some(path) => Some(io::read_barcode_whitelist(path)?),
+            None => None,
+        };
+        let mut mapped = count_mapped_reads(&args, &regions, targets.as_ref(), whitelist.as_ref())?;
+        let (unmapped, unmapped_read_groups) = if args.skip_unmapped {
+            (None, None)
+        } else {
+            let (unmapped, read_groups) = count_unmapped_reads(&args)?;
+            (Some(unmapped), Some(read_groups))
+        };
+        if let Some(path) = &args.output_bam {
+            eprintln!("Writing annotated reads to {}", path.display());
+            let bam = open_bam(&args.bamfile, args.reference.as_deref())?;
+            let header = bam.header();
+            // One part per chromosome, in header order, then unmapped reads
+            concatenate_parts(
+                path,
+                header.target_count() as usize + 1,
+                header,
+                &mapped.primary_assignments,
+            )?;
+        }
+        if let Some(path) = &args.bedgraph {
+            eprintln!("Writing exon coverage to {}", path.display());
+            let bam = open_bam(&args.bamfile, args.reference.as_deref())?;
+            concatenate_bedgraph_parts(path, bam.header().target_count() as usize)?;
+        }
+        if let Some(path) = &args.extract_output {
+            eprintln!("Writing extracted reads to {}", path.display());
+            let bam = open_bam(&args.bamfile, args.reference.as_deref())?;
+            let header = bam.header();
+            write_extracted(path, header.target_count() as usize + 1, header)?;
+        }
+        let chromosomes = if args.by_chromosome {
+            let counted = regions
+                .chromosomes()
+                .keys()
+                .map(|chrom| chrom.as_str())
+                .collect();
+            chromosome_reports(
+                &chromosome_lengths(&args.bamfile, args.reference.as_deref())?,
+                &counted,
+                &mapped.chromosomes,
+                regions.labels(),
+            )
+        } else {
+            vec![]
+        };
+        let spike_ins = match &args.spike_ins {
+            Some(spike_ins) => {
+                let concentrations = match &args.spike_in_concentrations {
+                    Some(path) => Some(io::read_spike_in_concentrations(path)?),
+                    None => None,
+                };
+                Some(spike_ins.report(&mapped.spike_ins, &mapped.all, concentrations.as_ref()))
+            }
+            None => None,
+        };
+        let genes = if args.gene_expression {
+            let fragment_length = args.fragment_length.or_else(|| {
+                let distributions = mapped.distributions.as_ref()?;
+                distributions.accepted.template_length.mean()
+            });
+            // Both mates of a pair overlap the gene, whatever the filters
+            let layout = match (args.library_layout, args.preset) {
+                (Some(layout), _) => Some(layout),
+                (None, Some(Preset::Paired)) => Some(LibraryLayout::Paired),
+                (None, Some(Preset::Single | Preset::LongRead)) => Some(LibraryLayout::Single),
+                (None, None) => detect_library_layout(&args.bamfile, args.reference.as_deref())?,
+            };
+            let reads_per_fragment = if layout == Some(LibraryLayout::Paired) {
+                2.0
+            } else {
+                1.0
+            };
+            // No chromosome may have been counted, leaving no classifications
+            let counts = gene_classifier
+                .and_then(|index| mapped.classifications.get(index).cloned())
+                .unwrap_or_else(|| vec![CountResult::default(); genes.len()]);
+            Some(gene_expression(
+                &genes,
+                &counts,
+                mapped.all.accepted,
+                reads_per_fragment,
+                fragment_length,
+            ))
+        } else {
+            None
+        };
+        let (equivalence_classes, transcript_compatibility) = match &args.transcripts {
+            Some(transcripts) => {
+                let (table, summary) = EquivalenceClassTable::new(
+                    transcripts.ids(),
+                    std::mem::take(&mut mapped.equivalence_classes.counts),
+                );
+                (Some(table), Some(summary))
+            }
+            None => (None, None),
+        };
+        let chimeric = match (&mapped.chimeric, &args.gene_spans) {
+            (Some(chimeric), Some(genes)) => Some(chimeric.report(genes, mapped.all.accepted)),
+            _ => None,
+        };
+        let introns = args.introns.as_ref().map(|introns| IntronTable {
+            introns: introns.introns().to_vec(),
+            counts: std::mem::take(&mut mapped.introns),
+        });
+        let coverage = args.coverage.then(|| CoverageTable {
+            thresholds: args.coverage_thresholds.clone(),
+            regions: std::mem::take(&mut mapped.coverage),
+        });
+        let mut total = mapped.all.clone();
+        if let Some(spike_ins) = &spike_ins {
+            total.merge(&spike_ins.total);
+        }
+        if let Some(unmapped) = &unmapped {
+            total.merge(unmapped);
+        }
+        Ok(CountReport {
+            options: ReportOptions {
+                minmapqual: args.filter.minmapqual,
+                required_flag: args.filter.required_flag,
+                filtered_flag: args.filter.filtered_flag,
+                expression: args.expression.as_ref().map(|e| e.text().to_string()),
+                preset: args.preset,
+                preset_detected: args.preset_detected,
+                min_exon_fraction: args.min_exon_fraction,
+                umi: args.umi_tag.clone().or_else(|| {
+                    args.umi_separator
+                        .map(|c| format!("read name after '{}'", c))
+                }),
+                umi_method: args.deduplicate().then_some(args.umi_method),
+                subsample_fraction: args.subsample_fraction,
+                subsample_reads: args.subsample_reads,
+                subsample_seed: args.subsample_fraction.map(|_| args.subsample_seed),
+                regions: args.regions.clone(),
+                targets: args.targets.clone(),
+                gtf: args.gtf.clone(),
+                bamfile: args.bamfile.clone(),
+            },
+            categories: mapped.categories,
+            expression_rejected: args.expression.as_ref().map(|_| mapped.expression_rejected),
+            mapped: mapped.all,
+            unmapped,
+            total,
+            distributions: mapped
+                .distributions
+                .filter(|_| args.distributions || args.long_read_mode()),
+            deduplicated: mapped.dedup,
+            duplicates: mapped.duplicates,
+            estimated_duplicates: mapped.estimated_duplicates,
+            saturation: mapped.saturation.as_ref().map(|s| s.report()),
+            read_groups: match &args.read_groups {
+                Some(groups) => read_group_reports(
+                    groups,
+                    &mapped.read_groups,
+                    unmapped_read_groups.as_deref(),
+                    regions.labels(),
+                ),
+                None => vec![],
+            },
+            spike_ins,
+            chromosomes,
+            // The category classifier is the main table
+            classifications: args
+                .classifiers
+                .iter()
+                .zip(&mapped.classifications)
+                .skip(CATEGORY_CLASSIFIER + 1)
+                .map(|(classifier, counts)| Classification::new(classifier.as_ref(), counts))
+                .collect(),
+            barcodes: mapped.barcodes,
+            genes,
+            transcript_compatibility,
+            equivalence_classes,
+            introns,
+            coverage,
+            chimeric,
+        })
+    }
+}
+
+// Collects the region and target file regions, clipped to the chromosome
+// lengths in the BAM header, or None if neither is given.
+fn read_targets(args: &Counter) -> Result<Option<HashMap<String, Vec<Region>>>, Error> {
+    if args.regions.is_empty() && args.targets.is_none() {
+        return Ok(None);
+    }
+    let mut targets = args
+        .regions
+        .iter()
+        .map(|region| parse_region(region))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(path) = &args.targets {
+        targets.extend(io::read_bed_regions(path)?);
+    }
+    let bam = open_bam(&args.bamfile, args.reference.as_deref())?;
+    let header = bam.header();
+    for target in targets.iter_mut() {
+        let length = header
+            .tid(target.seqname.as_bytes())
+            .and_then(|tid| header.target_len(tid))
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "Target chromosome {} is not in the BAM file",
+                    target.seqname
+                ))
+            })?;
+        target.end = target.end.min(length as i64);
+    }
+    targets.retain(|target| target.start < target.end);
+    if targets.is_empty() {
+        return Err(Error::msg(
+            "No target region lies within the chromosomes of the BAM file",
+        ));
+    }
+    Ok(Some(group_targets(targets)))
+}
+
+// Opens the BAM or CRAM file for fetching reads, with the reference for
+// decoding CRAM if one is given
+fn open_indexed(args: &Counter) -> Result<IndexedReader, Error> {
+    open_indexed_bam(&args.bamfile, args.reference.as_deref())
+}
+
+fn get_chrom_names(bamfile: &Path, reference: Option<&Path>) -> Result<Vec<String>, Error> {
+    let bam = open_bam(bamfile, reference)?;
+    let header = bam.header();
+    let chroms = header.target_names();
+    let chroms = chroms
+        .iter()
+        .map(|x| String::from_utf8(x.to_vec()))
+        .collect::<Result<Vec<_>, _>>();
+    match chroms {
+        Ok(chroms) => Ok(chroms),
+        Err(e) => Err(Error::msg(format!("Error reading chromosome names: {}", e))),
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CountResult {
+    pub accepted: usize,
+    pub rejected: usize,
+}
+
+impl CountResult {
+    pub fn add(&mut self, outcome: &ReadCheckOutcome) {
+        match outcome {
+            ReadCheckOutcome::Accept => self.accepted += 1,
+            ReadCheckOutcome::Reject => self.rejected += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &CountResult) {
+        self.accepted += other.accepted;
+        self.rejected += other.rejected;
+    }
+
+    pub fn total(&self) -> usize {
+        self.accepted + self.rejected
+    }
+}
+
+// Read counts of each label of the category classifier, the first of
+// `Counter::classifiers`. A read may have any number of labels, so mapped
+// reads are counted separately.
+#[derive(Debug, Clone, Default)]
+pub struct CategoryCounts {
+    labels: Vec<String>,
+    counts: Vec<CountResult>,
+    mapped: CountResult,
+}
+
+impl CategoryCounts {
+    pub fn new(labels: &[String]) -> Self {
+        CategoryCounts {
+            labels: labels.to_vec(),
+            counts: vec![CountResult::default(); labels.len()],
+            mapped: CountResult::default(),
+        }
+    }
+
+    // Counts with the same labels and no reads
+    pub fn empty(&self) -> Self {
+        CategoryCounts::new(&self.labels)
+    }
+
+    pub fn add(&mut self, classes: &[usize], outcome: &ReadCheckOutcome) {
+        for &class in classes {
+            self.counts[class].add(outcome);
+        }
+        self.mapped.add(outcome);
+    }
+
+    pub fn merge(&mut self, other: &CategoryCounts) {
+        if self.labels.is_empty() {
+            self.labels = other.labels.clone();
+            self.counts = vec![CountResult::default(); other.counts.len()];
+        }
+        for (counts, other_counts) in self.counts.iter_mut().zip(&other.counts) {
+            counts.merge(other_counts);
+        }
+        self.mapped.merge(&other.mapped);
+    }
+
+    pub fn mapped(&self) -> CountResult {
+        self.mapped.clone()
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    // Labels and their counts, in label order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &CountResult)> {
+        self.labels
+            .iter()
+            .map(|label| label.as_str())
+            .zip(&self.counts)
+    }
+
+    pub fn get(&self, label: &str) -> Option<&CountResult> {
+        self.iter()
+            .find(|(name, _)| *name == label)
+            .map(|(_, counts)| counts)
+    }
+}
+
+// Serialised as the counts of each label, keyed by the lower-cased label
+impl Serialize for CategoryCounts {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.labels.len()))?;
+        for (label, counts) in self.iter() {
+            map.serialize_entry(&label.to_lowercase(), counts)?;
+        }
+        map.end()
+    }
+}
+
+// What is known about a read before its category is decided
+#[derive(Debug, Clone)]
+pub(crate) struct ReadInfo {
+    pub(crate) outcome: ReadCheckOutcome,
+    pub(crate) cell: Option<CellTag>,
+    pub(crate) molecule: Option<Molecule>,
+    // Marked as a duplicate in the BAM file
+    pub(crate) duplicate: bool,
+    // Shares its fragment signature with an earlier read
+    pub(crate) estimated_duplicate: bool,
+    // Set for accepted reads when a saturation curve is requested
+    pub(crate) saturation: Option<SaturationRead>,
+    // Labels assigned by each classifier
+    pub(crate) classes: Vec<Vec<usize>>,
+    // Index of the read group, when counting by read group
+    pub(crate) read_group: Option<usize>,
+    // Contig of the read, when counting by chromosome
+    pub(crate) chromosome: Option<i32>,
+    // Transcripts compatible with an accepted read, when counting
+    // equivalence classes
+    pub(crate) equivalence_class: Option<ReadClass>,
+}
+
+// Counts accumulated over mapped reads, either for a single chromosome or
+// merged across all of them.
+#[derive(Default)]
+struct MappedCounts {
+    all: CountResult,
+    categories: CategoryCounts,
+    // Reads flagged as duplicates, and reads estimated to be duplicates from
+    // their fragment signature
+    duplicates: CategoryCounts,
+    estimated_duplicates: Option<CategoryCounts>,
+    distributions: Option<DistributionSet>,
+    // Accepted reads per cell barcode
+    barcodes: Option<HashMap<Vec<u8>, BarcodeCounts>>,
+    // Counts after UMI deduplication, and the UMIs seen at positions that
+    // may still receive reads
+    dedup: Option<CategoryCounts>,
+    umi_groups: UmiGroups,
+    // Long reads with supplementary alignments, keyed by read name, whose
+    // category can only be decided once all chromosomes have been counted
+    split_reads: HashMap<Vec<u8>, SplitRead>,
+    // Assignments of the primary alignments of split long reads, written to
+    // their supplementary alignments in --output-bam
+    primary_assignments: PrimaryAssignments,
+    saturation: Option<SaturationCounts>,
+    // Counts of each label of each classifier
+    classifications: Vec<Vec<CountResult>>,
+    // Reads that passed the flag and mapping quality filters but not the
+    // filter expression
+    expression_rejected: usize,
+    // Counts of each read group, when counting by read group
+    read_groups: Vec<CategoryCounts>,
+    // Counts of each contig by tid, when counting by chromosome
+    chromosomes: BTreeMap<i32, CategoryCounts>,
+    // Reads on each spike-in, which are not counted as mapped
+    spike_ins: Vec<CountResult>,
+    // Accepted fragments per set of compatible transcripts
+    equivalence_classes: FragmentClasses,
+    // Accepted reads of each intron, by index
+    introns: HashMap<usize, IntronCounts>,
+    // Accepted primary reads with supplementary alignments
+    chimeric: Option<ChimericCounts>,
+    // Depth of accepted reads over each merged exon region, in order
+    coverage: Vec<RegionCoverage>,
+}
+
+impl MappedCounts {
+    fn merge(&mut self, other: MappedCounts) {
+        self.all.merge(&other.all);
+        self.expression_rejected += other.expression_rejected;
+        self.categories.merge(&other.categories);
+        self.duplicates.merge(&other.duplicates);
+        if let Some(other_estimated) = &other.estimated_duplicates {
+            self.estimated_duplicates
+                .get_or_insert_with(CategoryCounts::default)
+                .merge(other_estimated);
+        }
+        if let Some(other_distributions) = &other.distributions {
+            self.distributions
+                .get_or_insert_with(DistributionSet::default)
+                .merge(other_distributions);
+        }
+        if let Some(other_dedup) = &other.dedup {
+            self.dedup
+                .get_or_insert_with(CategoryCounts::default)
+                .merge(other_dedup);
+        }
+        self.umi_groups.merge(other.umi_groups);
+        if let Some(other_barcodes) = other.barcodes {
+            merge_barcode_counts(
+                self.barcodes.get_or_insert_with(HashMap::new),
+                other_barcodes,
+            );
+        }
+        for (qname, split_read) in other.split_reads {
+            self.split_reads.entry(qname).or_default().merge(split_read);
+        }
+        self.primary_assignments.extend(other.primary_assignments);
+        if let Some(other_saturation) = other.saturation {
+            self.saturation
+                .get_or_insert_with(SaturationCounts::default)
+                .merge(other_saturation);
+        }
+        if self.spike_ins.is_empty() {
+            self.spike_ins = other.spike_ins;
+        } else {
+            for (counts, other_counts) in self.spike_ins.iter_mut().zip(&other.spike_ins) {
+                counts.merge(other_counts);
+            }
+        }
+        if let Some(other_chimeric) = &other.chimeric {
+            self.chimeric
+                .get_or_insert_with(ChimericCounts::default)
+                .merge(other_chimeric);
+        }
+        self.coverage.extend(other.coverage);
+        for (intron, other_counts) in &other.introns {
+            self.introns.entry(*intron).or_default().merge(other_counts);
+        }
+        self.equivalence_classes.merge(other.equivalence_classes);
+        for (tid, other_counts) in &other.chromosomes {
+            self.chromosomes
+                .entry(*tid)
+                .or_default()
+                .merge(other_counts);
+        }
+        if self.read_groups.is_empty() {
+            self.read_groups = other.read_groups;
+        } else {
+            for (counts, other_counts) in self.read_groups.iter_mut().zip(&other.read_groups) {
+                counts.merge(other_counts);
+            }
+        }
+        if self.classifications.is_empty() {
+            self.classifications = other.classifications;
+        } else {
+            for (counts, other_counts) in
+                self.classifications.iter_mut().zip(&other.classifications)
+            {
+                for (count, other_count) in counts.iter_mut().zip(other_counts) {
+                    count.merge(other_count);
+                }
+            }
+        }
+    }
+
+    // Keeps the assignment of a primary alignment with supplementary ones
+    fn add_primary_assignment(&mut self, read: &Record, assignment: Assignment, features: &[&str]) {
+        if read.flags() & FLAG_SUPPLEMENTARY == 0 && read.aux(b"SA").is_ok() {
+            let features = features.iter().map(|f| f.to_string()).collect();
+            self.primary_assignments
+                .insert(read.qname().to_vec(), (assignment, features));
+        }
+    }
+
+    fn add_read(&mut self, info: ReadInfo) {
+        let outcome = info.outcome;
+        let categories = &info.classes[CATEGORY_CLASSIFIER];
+        // Reads without a UMI cannot be deduplicated and count as unique
+        if let (Some(dedup), Some(molecule)) = (self.dedup.as_mut(), info.molecule) {
+            let key = molecule.key();
+            match molecule.umi {
+                Some(umi) => self.umi_groups.add(key, umi, categories, &outcome),
+                None => dedup.add(categories, &outcome),
+            }
+        }
+        self.categories.add(categories, &outcome);
+        if let Some(read_group) = info.read_group {
+            self.read_groups[read_group].add(categories, &outcome);
+        }
+        if let Some(class) = info.equivalence_class {
+            self.equivalence_classes.add(class);
+        }
+        if let Some(tid) = info.chromosome {
+            self.chromosomes
+                .entry(tid)
+                .or_insert_with(|| self.categories.empty())
+                .add(categories, &outcome);
+        }
+        for (counts, classes) in self.classifications.iter_mut().zip(&info.classes) {
+            for &class in classes {
+                counts[class].add(&outcome);
+            }
+        }
+        if let (Some(saturation), Some(read)) = (self.saturation.as_mut(), info.saturation) {
+            saturation.add(read, categories.contains(&RegionClassifier::EXON));
+        }
+        if info.duplicate {
+            self.duplicates.add(categories, &outcome);
+        }
+        if let (true, Some(estimated)) =
+            (info.estimated_duplicate, self.estimated_duplicates.as_mut())
+        {
+            estimated.add(categories, &outcome);
+        }
+        if let (ReadCheckOutcome::Accept, Some(barcodes), Some(cell)) =
+            (outcome, self.barcodes.as_mut(), info.cell)
+        {
+            barcodes
+                .entry(cell.barcode.clone())
+                .or_default()
+                .add(categories, &cell);
+        }
+    }
+
+    // Counts a long read in the category of its aligned bases, rather than
+    // of its primary alignment alone
+    fn add_long_read(
+        &mut self,
+        bases: &AlignedBases,
+        mut info: ReadInfo,
+        regions: &RegionClassifier,
+        min_exon_fraction: Option<f64>,
+    ) {
+        let outcome = info.outcome;
+        info.classes[CATEGORY_CLASSIFIER] = regions
+            .label(bases.category(min_exon_fraction))
+            .into_iter()
+            .collect();
+        self.add_read(info);
+        if let Some(distributions) = self.distributions.as_mut() {
+            let percent = (bases.exon_fraction() * 100.0).floor() as i64;
+            match outcome {
+                ReadCheckOutcome::Accept => distributions.accepted.exon_fraction.add(percent),
+                ReadCheckOutcome::Reject => distributions.rejected.exon_fraction.add(percent),
+            }
+        }
+    }
+
+    // Counts split long reads once the bases of all their segments have been
+    // collected. Segments whose primary alignment was filtered out are dropped.
+    // Returns the names of the reads of the `extract` categories.
+    fn resolve_split_reads(
+        &mut self,
+        regions: &RegionClassifier,
+        min_exon_fraction: Option<f64>,
+        extract: &[ExtractCategory],
+    ) -> HashSet<Vec<u8>> {
+        let mut extracted = HashSet::new();
+        let split_reads = std::mem::take(&mut self.split_reads);
+        for (qname, split_read) in split_reads {
+            if let Some(primary) = split_read.primary {
+                let category = split_read.bases.category(min_exon_fraction);
+                if is_extracted(extract, Some(category.into()), primary.outcome) {
+                    extracted.insert(qname);
+                }
+                self.add_long_read(&split_read.bases, primary, regions, min_exon_fraction);
+            }
+        }
+        extracted
+    }
+
+    // Counts the molecules of all UMI groups before the given position, or
+    // of all remaining groups if there is none
+    fn flush_umi_groups(&mut self, before: Option<(i32, i64)>, method: UmiMethod) {
+        if let Some(dedup) = self.dedup.as_mut() {
+            let groups = match before {
+                Some((tid, pos)) => self.umi_groups.drain_before(tid, pos),
+                None => self.umi_groups.drain_all(),
+            };
+            add_molecules(dedup, groups, method);
+        }
+    }
+}
+
+fn count_reads(
+    chrom: &str,
+    tid: u32,
+    classifier: &RegionClassifier,
+    targets: Option<&[Region]>,
+    args: &Counter,
+    whitelist: Option<&HashSet<Vec<u8>>>,
+) -> Result<MappedCounts, Error> {
+    // Only count unique reads
+    let mut counts = MappedCounts::default();
+    let long_read = args.long_read_mode();
+    // Gene expression needs the template lengths for the fragment length
+    if args.distributions || long_read || args.gene_expression {
+        counts.distributions = Some(DistributionSet::default());
+    }
+    if args.cell_barcodes {
+        counts.barcodes = Some(HashMap::new());
+    }
+    counts.categories = CategoryCounts::new(classifier.labels());
+    counts.duplicates = counts.categories.empty();
+    if args.deduplicate() {
+        counts.dedup = Some(counts.categories.empty());
+    }
+    let mut fragments = FragmentTracker::default();
+    if args.estimate_duplicates {
+        counts.estimated_duplicates = Some(counts.categories.empty());
+    }
+    if args.saturation {
+        counts.saturation = Some(SaturationCounts::default());
+    }
+    if args.gene_spans.is_some() {
+        counts.chimeric = Some(ChimericCounts::default());
+    }
+    if let Some(spike_ins) = &args.spike_ins {
+        counts.spike_ins = vec![CountResult::default(); spike_ins.len()];
+    }
+    if let Some(read_groups) = &args.read_groups {
+        counts.read_groups = vec![counts.categories.empty(); read_groups.group_count()];
+    }
+    counts.classifications = args
+        .classifiers
+        .iter()
+        .map(|classifier| vec![CountResult::default(); classifier.labels().len()])
+        .collect();
+    // In long-read mode supplementary alignments are attributed to their
+    // primary read instead of being dropped
+    let always_filtered = if long_read {
+        FLAGS_ALWAYS_FILTERED & !FLAG_SUPPLEMENTARY
+    } else {
+        FLAGS_ALWAYS_FILTERED
+    };
+    let subsample = args.subsample();
+
+    let mut bam = open_indexed(args)?;
+    let mut annotated = match &args.output_bam {
+        Some(path) => Some(PartWriter::create(path, tid as usize, bam.header())?),
+        None => None,
+    };
+    let mut extracted = match &args.extract_output {
+        Some(path) => Some(PartWriter::create(path, tid as usize, bam.header())?),
+        None => None,
+    };
+    let regions = &classifier.chromosomes()[chrom];
+    let mut coverage = (args.coverage || args.bedgraph.is_some())
+        .then(|| CoverageAccumulator::new(&regions.exons));
+    let mut read = Record::new();
+    // With targets, reads are fetched from each target window in turn, and a
+    // read overlapping several windows is only counted in the first
+    let mut windows = targets.unwrap_or_default().iter();
+    let mut counted_until = i64::MIN;
+    let mut window_end = i64::MIN;
+    if targets.is_none() {
+        bam.fetch(chrom)?;
+    }
+
+    let exons = &regions.exons;
+    let genes = &regions.genes;
+    let mut current_region_index = 0;
+    let mut current_gene_index = 0;
+    let mut gene_cursor = GeneCursor::new(&regions.gene_spans);
+
+    loop {
+        let result = match bam.read(&mut read) {
+            Some(result) => result,
+            None => match windows.next() {
+                Some(window) => {
+                    counted_until = window_end;
+                    window_end = window.end;
+                    bam.fetch((chrom, window.start, window.end))?;
+                    continue;
+                }
+                None => break,
+            },
+        };
+        match result {
+            Ok(_) => {
+                if read.pos() < counted_until {
+                    continue;
+                }
+                if subsample.is_some_and(|s| !s.keeps(read.qname())) {
+                    continue;
+                }
+                if read.flags() & always_filtered != 0 {
+                    if let Some(writer) = annotated.as_mut() {
+                        writer.write(&read, Some(Assignment::Flag), &[])?;
+                        if long_read {
+                            counts.add_primary_assignment(&read, Assignment::Flag, &[]);
+                        }
+                    }
+                    continue;
+                }
+
+                // Skip past any regions that end before the read starts
+                current_region_index = advance_past(exons, current_region_index, read.pos());
+                current_gene_index = advance_past(genes, current_gene_index, read.pos());
+
+                // Long reads are scored by the number of their aligned bases
+                // that fall in exons and genes
+                let long_read_bases = if long_read {
+                    let blocks = cigar_blocks(&read);
+                    let bases = AlignedBases {
+                        aligned: blocks.iter().map(|(start, end)| end - start).sum(),
+                        exon: overlap_length(&blocks, &exons[current_region_index..]),
+                        gene: overlap_length(&blocks, &genes[current_gene_index..]),
+                    };
+                    if read.flags() & FLAG_SUPPLEMENTARY != 0 {
+                        // Counted with its primary alignment, and tagged with
+                        // its assignment when the parts are concatenated
+                        if let Some(writer) = annotated.as_mut() {
+                            writer.write(&read, None, &[])?;
+                        }
+                        counts
+                            .split_reads
+                            .entry(read.qname().to_vec())
+                            .or_default()
+                            .bases
+                            .add(&bases);
+                        continue;
+                    }
+                    Some(bases)
+                } else {
+                    None
+                };
+
+                let mut read_check_outcome = args.filter.check(&read);
+                let mut assignment = filter_assignment(&args.filter, &read);
+                if let (ReadCheckOutcome::Accept, Some(expression)) =
+                    (read_check_outcome, &args.expression)
+                {
+                    if !expression.matches(&read) {
+                        read_check_outcome = ReadCheckOutcome::Reject;
+                        assignment = Some(Assignment::Filter);
+                        counts.expression_rejected += 1;
+                    }
+                }
+                let spike_in = args.spike_ins.as_ref().and_then(|s| s.find(chrom, &read));
+                if let Some(writer) = annotated.as_mut() {
+                    let features = assigned_features(args, chrom, &read, spike_in);
+                    let assignment =
+                        assignment.unwrap_or_else(|| Assignment::from_features(&features));
+                    writer.write(&read, Some(assignment), &features)?;
+                    if long_read {
+                        counts.add_primary_assignment(&read, assignment, &features);
+                    }
+                }
+                if let Some(index) = spike_in {
+                    extract_read(extracted.as_mut(), args, &read, None, read_check_outcome)?;
+                    counts.spike_ins[index].add(&read_check_outcome);
+                    continue;
+                }
+                counts.all.add(&read_check_outcome);
+                if let (ReadCheckOutcome::Accept, Some(introns)) =
+                    (read_check_outcome, &args.introns)
+                {
+                    introns.count(chrom, &cigar_spliced_segments(&read), &mut counts.introns);
+                }
+                if let (ReadCheckOutcome::Accept, Some(coverage)) =
+                    (read_check_outcome, coverage.as_mut())
+                {
+                    coverage.add(&cigar_blocks(&read));
+                }
+                if let (ReadCheckOutcome::Accept, Some(chimeric), Some(genes)) = (
+                    read_check_outcome,
+                    counts.chimeric.as_mut(),
+                    &args.gene_spans,
+                ) {
+                    chimeric.add(chrom, &read, &cigar_blocks(&read), genes);
+                }
+
+                if let Some(distributions) = counts.distributions.as_mut() {
+                    match read_check_outcome {
+                        ReadCheckOutcome::Accept => distributions.accepted.add(&read),
+                        ReadCheckOutcome::Reject => distributions.rejected.add(&read),
+                    }
+                }
+
+                let info = ReadInfo {
+                    outcome: read_check_outcome,
+                    cell: counts.barcodes.as_ref().and_then(|_| {
+                        cell_tag(&read, &args.cell_barcode_tag, &args.cell_umi_tag, whitelist)
+                    }),
+                    molecule: counts.dedup.as_ref().map(|_| {
+                        let umi = read_umi(&read, args.umi_tag.as_deref(), args.umi_separator);
+                        Molecule::from_read(&read, umi)
+                    }),
+                    duplicate: read.flags() & FLAG_DUPLICATE != 0,
+                    estimated_duplicate: args.estimate_duplicates
+                        && fragments.is_duplicate(FragmentKey::from_read(&read)),
+                    saturation: match (read_check_outcome, &counts.saturation) {
+                        (ReadCheckOutcome::Accept, Some(_)) => Some(SaturationRead {
+                            bin: subsample_bin(read_name_fraction(read.qname(), SATURATION_SEED)),
+                            fragment: (!read.is_last_in_template())
+                                .then(|| FragmentKey::from_read(&read)),
+                            genes: gene_cursor
+                                .overlapping(&cigar_blocks(&read))
+                                .iter()
+                                .map(|gene| gene.index)
+                                .collect(),
+                        }),
+                        _ => None,
+                    },
+                    classes: classify_read(&args.classifiers, chrom, &read),
+                    read_group: args.read_groups.as_ref().map(|g| g.index_of(&read)),
+                    chromosome: args.by_chromosome.then(|| read.tid()),
+                    equivalence_class: match (read_check_outcome, &args.transcripts) {
+                        (ReadCheckOutcome::Accept, Some(transcripts)) => Some(ReadClass {
+                            qname: read.qname().to_vec(),
+                            pos: read.pos(),
+                            // Only a primary mate on this chromosome is joined
+                            mate_pos: (read.is_paired()
+                                && !read.is_mate_unmapped()
+                                && read.mtid() == read.tid()
+                                && !read.is_secondary()
+                                && !read.is_supplementary())
+                            .then(|| read.mpos()),
+                            transcripts: transcripts
+                                .compatible(chrom, &cigar_spliced_segments(&read)),
+                        }),
+                        _ => None,
+                    },
+                };
+                // No later read can have its 5' end, or belong to a fragment
+                // that ends, this far before the current read, so earlier UMI
+                // groups and fragments are complete
+                counts.flush_umi_groups(
+                    Some((read.tid(), read.pos() - FLUSH_WINDOW)),
+                    args.umi_method,
+                );
+                fragments.forget_before(read.tid(), read.pos() - FLUSH_WINDOW);
+                counts.equivalence_classes.flush_before(read.pos());
+                if let Some(saturation) = counts.saturation.as_mut() {
+                    saturation.flush_fragments(Some(FragmentKey::first_at(
+                        read.tid(),
+                        read.pos() - FLUSH_WINDOW,
+                    )));
+                }
+
+                if let Some(bases) = long_read_bases {
+                    if read.aux(b"SA").is_ok() {
+                        counts
+                            .split_reads
+                            .entry(read.qname().to_vec())
+                            .or_default()
+                            .merge(SplitRead {
+                                bases,
+                                primary: Some(info),
+                            });
+                    } else {
+                        let category = bases.category(args.min_exon_fraction);
+                        extract_read(
+                            extracted.as_mut(),
+                            args,
+                            &read,
+                            Some(category.into()),
+                            read_check_outcome,
+                        )?;
+                        counts.add_long_read(&bases, info, classifier, args.min_exon_fraction);
+                    }
+                    continue;
+                }
+
+                if extracted.is_some() {
+                    // Extraction knows all three region categories, whatever
+                    // the labels of the main table
+                    let category = classifier.category(chrom, &read, &cigar_blocks(&read));
+                    extract_read(
+                        extracted.as_mut(),
+                        args,
+                        &read,
+                        Some(category.into()),
+                        read_check_outcome,
+                    )?;
+                }
+                counts.add_read(info);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    counts.flush_umi_groups(None, args.umi_method);
+    if let Some(saturation) = counts.saturation.as_mut() {
+        saturation.flush_fragments(None);
+    }
+    counts.equivalence_classes.flush_before(i64::MAX);
+    if let Some(coverage) = coverage {
+        let mut bedgraph = match &args.bedgraph {
+            Some(path) => Some(create_bedgraph_part(path, tid as usize)?),
+            None => None,
+        };
+        counts.coverage = coverage.finish(
+            &args.coverage_thresholds,
+            bedgraph.as_mut().map(|out| out as &mut dyn Write),
+        )?;
+        if let Some(out) = bedgraph.as_mut() {
+            out.flush()?;
+        }
+    }
+    Ok(counts)
+}
+
+// Labels of a read from each classifier, starting with its categories
+fn classify_read(
+    classifiers: &[Arc<dyn ReadClassifier>],
+    chrom: &str,
+    read: &Record,
+) -> Vec<Vec<usize>> {
+    let blocks = cigar_blocks(read);
+    classifiers
+        .iter()
+        .map(|classifier| {
+            let mut classes = vec![];
+            classifier.classify(chrom, read, &blocks, &mut classes);
+            classes
+        })
+        .collect()
+}
+
+// Writes a mapped read to the extracted reads if its category is extracted.
+// Reads flagged as unmapped but placed at the position of their mate are
+// extracted as unmapped, with the filter of count_unmapped_reads.
+fn extract_read(
+    writer: Option<&mut PartWriter>,
+    args: &Counter,
+    read: &Record,
+    category: Option<ExtractCategory>,
+    outcome: ReadCheckOutcome,
+) -> Result<(), Error> {
+    let Some(writer) = writer else {
+        return Ok(());
+    };
+    let (category, outcome) = if read.is_unmapped() {
+        (
+            Some(ExtractCategory::Unmapped),
+            unmapped_filter(args.filter).check(read),
+        )
+    } else {
+        (category, outcome)
+    };
+    if is_extracted(&args.extract, category, outcome) {
+        writer.write(read, None, &[])?;
+    }
+    Ok(())
+}
+
+// Spike-in or genes a read is assigned to in --output-bam: the spike-in it
+// is on, or the genes whose exons it overlaps
+fn assigned_features<'a>(
+    args: &'a Counter,
+    chrom: &str,
+    read: &Record,
+    spike_in: Option<usize>,
+) -> Vec<&'a str> {
+    if let (Some(index), Some(spike_ins)) = (spike_in, &args.spike_ins) {
+        return vec![spike_ins.name(index)];
+    }
+    let Some(genes) = &args.assignment_genes else {
+        return vec![];
+    };
+    let mut classes = vec![];
+    genes.classify(chrom, read, &cigar_blocks(read), &mut classes);
+    classes
+        .into_iter()
+        .map(|class| genes.labels()[class].as_str())
+        .collect()
+}
+
+fn count_mapped_reads(
+    args: &Counter,
+    regions: &RegionClassifier,
+    targets: Option<&HashMap<String, Vec<Region>>>,
+    whitelist: Option<&HashSet<Vec<u8>>>,
+) -> Result<MappedCounts, Error> {
+    // Keeps the category rows when no chromosome is counted
+    let mut counts = MappedCounts {
+        categories: CategoryCounts::new(regions.labels()),
+        duplicates: CategoryCounts::new(regions.labels()),
+        ..MappedCounts::default()
+    };
+
+    // Output parts are numbered by tid, so chromosomes of the annotation
+    // that are not in the BAM file are rejected before any part is written
+    let bam = open_bam(&args.bamfile, args.reference.as_deref())?;
+    let mut chroms = regions
+        .chromosomes()
+        .keys()
+        .map(|chrom| match bam.header().tid(chrom.as_bytes()) {
+            Some(tid) => Ok((chrom.as_str(), tid)),
+            None => Err(Error::msg(format!(
+                "Chromosome {} is not in the BAM file",
+                chrom
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // In header order, which is the order of the coverage table and of the
+    // concatenated parts
+    chroms.sort_by_key(|&(_, tid)| tid);
+
+    let results: Vec<Result<MappedCounts, Error>> = chroms
+        .par_iter()
+        .map(|&(chrom, tid)| {
+            eprintln!("Counting reads on chromosome {}", chrom);
+            let targets = targets.map(|t| t.get(chrom).map_or(&[][..], |t| t.as_slice()));
+            count_reads(chrom, tid, regions, targets, args, whitelist)
+        })
+        .collect();
+
+    for result in results {
+        counts.merge(result?);
+    }
+    let extracted = counts.resolve_split_reads(regions, args.min_exon_fraction, &args.extract);
+    if let (Some(path), false) = (&args.extract_output, extracted.is_empty()) {
+        extract_split_reads(args, path, &chroms, &extracted)?;
+    }
+    counts.flush_umi_groups(None, args.umi_method);
+    if let Some(saturation) = counts.saturation.as_mut() {
+        saturation.flush_fragments(None);
+    }
+
+    Ok(counts)
+}
+
+// Writes the primary alignments of split long reads, which are only
+// categorised once all chromosomes are counted, in a second pass over the
+// BAM file. Each chromosome gets a part that write_extracted merges with the
+// reads extracted while counting.
+fn extract_split_reads(
+    args: &Counter,
+    output: &Path,
+    chroms: &[(&str, u32)],
+    qnames: &HashSet<Vec<u8>>,
+) -> Result<(), Error> {
+    let output = split_read_parts(output);
+    chroms
+        .par_iter()
+        .map(|&(chrom, tid)| {
+            let mut bam = open_indexed(args)?;
+            bam.fetch(chrom)?;
+            let mut writer = None;
+            let mut read = Record::new();
+            while let Some(result) = bam.read(&mut read) {
+                match result {
+                    Ok(_) => {
+                        if read.flags() & FLAGS_ALWAYS_FILTERED != 0
+                            || !qnames.contains(read.qname())
+                        {
+                            continue;
+                        }
+                        let writer = match writer.as_mut() {
+                            Some(writer) => writer,
+                            None => writer.insert(PartWriter::create(
+                                &output,
+                                tid as usize,
+                                bam.header(),
+                            )?),
+                        };
+                        writer.write(&read, None, &[])?;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(())
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    Ok(())
+}
+
+// The filter for unmapped reads derived from the filter for mapped reads
+fn unmapped_filter(mut filter: ReadFilter) -> ReadFilter {
+    filter.minmapqual = 0;
+    filter.required_flag ^= filter.required_flag & FLAG_PROPER_PAIR; // Turn off mapping requirement
+    filter.required_flag |= FLAG_UNMAPPED; // Turn on unmapped requirement
+    filter.filtered_flag ^= filter.filtered_flag & FLAGS_MAPPING_RELATED; // Turn off mapping related flags
+    filter
+}
+
+// Counts unmapped reads, in total and for each read group when counting by
+// read group
+fn count_unmapped_reads(args: &Counter) -> Result<(CountResult, Vec<CountResult>), Error> {
+    let filter = unmapped_filter(args.filter);
+    let mut bam = open_indexed(args)?;
+    // Unmapped reads are the last part of the output BAM file
+    let mut annotated = match &args.output_bam {
+        Some(path) => Some(PartWriter::create(
+            path,
+            bam.header().target_count() as usize,
+            bam.header(),
+        )?),
+        None => None,
+    };
+    let mut extracted = match &args.extract_output {
+        Some(path) => Some(PartWriter::create(
+            path,
+            bam.header().target_count() as usize,
+            bam.header(),
+        )?),
+        None => None,
+    };
+    bam.fetch("*")?;
+    let mut read = Record::new();
+    let mut unmapped = CountResult::default();
+    let mut read_groups = match &args.read_groups {
+        Some(groups) => vec![CountResult::default(); groups.group_count()],
+        None => vec![],
+    };
+    let subsample = args.subsample();
+    while let Some(result) = bam.read(&mut read) {
+        match result {
+            Ok(_) => {
+                if subsample.is_some_and(|s| !s.keeps(read.qname())) {
+                    continue;
+                }
+                if read.flags() & FLAGS_ALWAYS_FILTERED != 0 {
+                    if let Some(writer) = annotated.as_mut() {
+                        writer.write(&read, Some(Assignment::Flag), &[])?;
+                    }
+                    continue;
+                }
+
+                if let Some(writer) = annotated.as_mut() {
+                    writer.write(&read, Some(Assignment::Unmapped), &[])?;
+                }
+                let outcome = filter.check(&read);
+                if let Some(writer) = extracted.as_mut() {
+                    if is_extracted(&args.extract, Some(ExtractCategory::Unmapped), outcome) {
+                        writer.write(&read, None, &[])?;
+                    }
+                }
+                unmapped.add(&outcome);
+                if let Some(groups) = &args.read_groups {
+                    read_groups[groups.index_of(&read)].add(&outcome);
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok((unmapped, read_groups))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unmapped_filter_requires_unmapped() {
+        let filter = unmapped_filter(ReadFilter::default());
+        assert_eq!(filter.required_flag, 1 | FLAG_UNMAPPED);
+        assert_eq!(filter.minmapqual, 0);
+
+        // A required flag that already includes the unmapped bit keeps it
+        let filter = unmapped_filter(ReadFilter {
+            minmapqual: 35,
+            required_flag: 7,
+            filtered_flag: 2816,
+        });
+        assert_eq!(filter.required_flag & FLAG_UNMAPPED, FLAG_UNMAPPED);
+    }
+}
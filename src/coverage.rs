@@ -0,0 +1,223 @@
+use crate::annotate::part_path;
+use crate::regions::Region;
+use anyhow::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+// Depth of the aligned blocks of accepted reads over the merged exon
+// regions of one chromosome. Depths are only allocated for regions that
+// some read reaches.
+pub struct CoverageAccumulator<'a> {
+    regions: &'a [Region],
+    depths: Vec<Vec<u32>>,
+}
+
+// Depth summary of one merged exon region
+#[derive(Debug, Clone)]
+pub struct RegionCoverage {
+    pub seqname: String,
+    pub start: i64,
+    pub end: i64,
+    pub mean: f64,
+    pub median: f64,
+    // Fraction of bases at or above each threshold
+    pub covered: Vec<f64>,
+}
+
+impl<'a> CoverageAccumulator<'a> {
+    // `regions` are sorted and non-overlapping, as produced by
+    // compress_regions
+    pub fn new(regions: &'a [Region]) -> Self {
+        CoverageAccumulator {
+            regions,
+            depths: vec![vec![]; regions.len()],
+        }
+    }
+
+    pub fn add(&mut self, blocks: &[(i64, i64)]) {
+        for &(start, end) in blocks {
+            let first = self.regions.partition_point(|region| region.end <= start);
+            for (region, depths) in self.regions[first..].iter().zip(&mut self.depths[first..]) {
+                if region.start >= end {
+                    break;
+                }
+                if depths.is_empty() {
+                    *depths = vec![0; (region.end - region.start) as usize];
+                }
+                let from = (start.max(region.start) - region.start) as usize;
+                let to = (end.min(region.end) - region.start) as usize;
+                for depth in &mut depths[from..to] {
+                    *depth += 1;
+                }
+            }
+        }
+    }
+
+    // Summarises the depth of each region, and writes the runs of non-zero
+    // depth as bedGraph lines if `bedgraph` is given
+    pub fn finish(
+        self,
+        thresholds: &[u32],
+        mut bedgraph: Option<&mut dyn Write>,
+    ) -> Result<Vec<RegionCoverage>, Error> {
+        let mut coverage = vec![];
+        for (region, mut depths) in self.regions.iter().zip(self.depths) {
+            let length = (region.end - region.start) as usize;
+            if depths.is_empty() {
+                coverage.push(RegionCoverage {
+                    seqname: region.seqname.clone(),
+                    start: region.start,
+                    end: region.end,
+                    mean: 0.0,
+                    median: 0.0,
+                    covered: vec![0.0; thresholds.len()],
+                });
+                continue;
+            }
+            if let Some(out) = bedgraph.as_mut() {
+                write_runs(*out, region, &depths)?;
+            }
+            depths.sort_unstable();
+            let total: u64 = depths.iter().map(|&depth| depth as u64).sum();
+            let median = if length % 2 == 1 {
+                depths[length / 2] as f64
+            } else {
+                (depths[length / 2 - 1] as f64 + depths[length / 2] as f64) / 2.0
+            };
+            coverage.push(RegionCoverage {
+                seqname: region.seqname.clone(),
+                start: region.start,
+                end: region.end,
+                mean: total as f64 / length as f64,
+                median,
+                covered: thresholds
+                    .iter()
+                    .map(|&threshold| {
+                        let below = depths.partition_point(|&depth| depth < threshold);
+                        (length - below) as f64 / length as f64
+                    })
+                    .collect(),
+            })
+        }
+        Ok(coverage)
+    }
+}
+
+// Writes the runs of equal, non-zero depth of a region as 0-based,
+// half-open bedGraph intervals
+fn write_runs(out: &mut dyn Write, region: &Region, depths: &[u32]) -> Result<(), Error> {
+    let mut run_start = 0;
+    for i in 1..=depths.len() {
+        if i < depths.len() && depths[i] == depths[run_start] {
+            continue;
+        }
+        if depths[run_start] > 0 {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}",
+                region.seqname,
+                region.start + run_start as i64,
+                region.start + i as i64,
+                depths[run_start]
+            )?;
+        }
+        run_start = i;
+    }
+    Ok(())
+}
+
+// Depth summaries of all merged exon regions, by chromosome in the order of
+// the BAM header and by position
+#[derive(Debug, Clone)]
+pub struct CoverageTable {
+    pub thresholds: Vec<u32>,
+    pub regions: Vec<RegionCoverage>,
+}
+
+impl CoverageTable {
+    // Writes one row per region with 1-based inclusive coordinates
+    pub fn write(&self, out: &mut impl Write) -> Result<(), Error> {
+        write!(
+            out,
+            "#Chromosome\tStart\tEnd\tLength\tMeanDepth\tMedianDepth"
+        )?;
+        for threshold in &self.thresholds {
+            write!(out, "\tCovered{}x", threshold)?;
+        }
+        writeln!(out)?;
+        for region in &self.regions {
+            write!(
+                out,
+                "{}\t{}\t{}\t{}\t{:.2}\t{:.1}",
+                region.seqname,
+                region.start + 1,
+                region.end,
+                region.end - region.start,
+                region.mean,
+                region.median
+            )?;
+            for fraction in &region.covered {
+                write!(out, "\t{:.4}", fraction)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+// Opens the bedGraph part of one chromosome
+pub fn create_bedgraph_part(output: &Path, part: usize) -> Result<BufWriter<File>, Error> {
+    Ok(BufWriter::new(File::create(part_path(output, part))?))
+}
+
+// Concatenates the bedGraph parts that were written, in order, into the
+// output file and removes them
+pub fn concatenate_bedgraph_parts(output: &Path, parts: usize) -> Result<(), Error> {
+    let mut out = BufWriter::new(File::create(output)?);
+    for part in 0..parts {
+        let path = part_path(output, part);
+        if !path.exists() {
+            continue;
+        }
+        std::io::copy(&mut BufReader::new(File::open(&path)?), &mut out)?;
+        std::fs::remove_file(&path)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: i64, end: i64) -> Region {
+        Region {
+            seqname: "chr1".to_string(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn test_coverage() {
+        let regions = [region(100, 110), region(200, 204), region(300, 310)];
+        let mut coverage = CoverageAccumulator::new(&regions);
+        // Spliced from the first region into the second
+        coverage.add(&[(95, 105), (200, 202)]);
+        coverage.add(&[(100, 104)]);
+        let mut bedgraph = vec![];
+        let table = coverage.finish(&[1, 2], Some(&mut bedgraph)).unwrap();
+        assert_eq!(
+            String::from_utf8(bedgraph).unwrap(),
+            "chr1\t100\t104\t2\nchr1\t104\t105\t1\nchr1\t200\t202\t1\n"
+        );
+        assert_eq!(table.len(), 3);
+        assert_eq!(table[0].mean, 0.9);
+        assert_eq!(table[0].median, 0.5);
+        assert_eq!(table[0].covered, vec![0.5, 0.4]);
+        assert_eq!(table[1].mean, 0.5);
+        assert_eq!(table[1].covered, vec![0.5, 0.0]);
+        assert_eq!((table[2].mean, table[2].covered[0]), (0.0, 0.0));
+    }
+}
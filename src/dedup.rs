@@ -0,0 +1,317 @@
+use crate::cigar::cigar_five_prime_pos;
+use crate::UmiMethod;
+use crate::{CategoryCounts, ReadCheckOutcome};
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::Record;
+use std::collections::{BTreeMap, HashMap};
+
+// Reads are duplicates of each other if they share a key and a UMI. The
+// position is the unclipped 5' end of the read, so the key is ordered by
+// chromosome and position first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DedupKey {
+    pub tid: i32,
+    pub pos: i64,
+    pub reverse: bool,
+    pub second_in_pair: bool,
+}
+
+// The reads with one UMI at a key. The molecule is counted in the
+// categories and filter outcome of its representative read: the first
+// accepted read, or the first read if none was accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UmiReads {
+    pub count: usize,
+    pub categories: Vec<usize>,
+    pub accepted: bool,
+}
+
+impl UmiReads {
+    fn merge(&mut self, other: UmiReads) {
+        self.count += other.count;
+        if other.accepted && !self.accepted {
+            self.categories = other.categories;
+            self.accepted = true;
+        }
+    }
+}
+
+// Position, strand and UMI of a read, used to recognise duplicates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Molecule {
+    pub tid: i32,
+    pub pos: i64,
+    pub reverse: bool,
+    pub second_in_pair: bool,
+    pub umi: Option<Vec<u8>>,
+}
+
+impl Molecule {
+    pub fn from_read(read: &Record, umi: Option<Vec<u8>>) -> Self {
+        Molecule {
+            tid: read.tid(),
+            pos: cigar_five_prime_pos(read),
+            reverse: read.is_reverse(),
+            second_in_pair: read.is_last_in_template(),
+            umi,
+        }
+    }
+
+    pub fn key(&self) -> DedupKey {
+        DedupKey {
+            tid: self.tid,
+            pos: self.pos,
+            reverse: self.reverse,
+            second_in_pair: self.second_in_pair,
+        }
+    }
+}
+
+// Takes the UMI from an aux tag, or from the last `separator`-delimited
+// field of the read name (the UMI-tools convention).
+pub fn read_umi(
+    read: &Record,
+    umi_tag: Option<&str>,
+    name_separator: Option<char>,
+) -> Option<Vec<u8>> {
+    if let Some(tag) = umi_tag {
+        return match read.aux(tag.as_bytes()) {
+            Ok(Aux::String(value)) => Some(value.as_bytes().to_vec()),
+            _ => None,
+        };
+    }
+    let separator = name_separator? as u8;
+    let qname = read.qname();
+    let start = qname.iter().rposition(|&c| c == separator)? + 1;
+    Some(qname[start..].to_vec())
+}
+
+// UMIs and their read counts at each key, for keys that may still receive
+// reads.
+#[derive(Debug, Default)]
+pub struct UmiGroups {
+    groups: BTreeMap<DedupKey, HashMap<Vec<u8>, UmiReads>>,
+}
+
+impl UmiGroups {
+    pub fn add(
+        &mut self,
+        key: DedupKey,
+        umi: Vec<u8>,
+        categories: &[usize],
+        outcome: &ReadCheckOutcome,
+    ) {
+        let reads = UmiReads {
+            count: 1,
+            categories: categories.to_vec(),
+            accepted: matches!(outcome, ReadCheckOutcome::Accept),
+        };
+        let group = self.groups.entry(key).or_default();
+        match group.get_mut(&umi) {
+            Some(current) => current.merge(reads),
+            None => {
+                group.insert(umi, reads);
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: UmiGroups) {
+        for (key, umis) in other.groups {
+            let group = self.groups.entry(key).or_default();
+            for (umi, reads) in umis {
+                match group.get_mut(&umi) {
+                    Some(current) => current.merge(reads),
+                    None => {
+                        group.insert(umi, reads);
+                    }
+                }
+            }
+        }
+    }
+
+    // Removes and returns the groups before position `pos` on chromosome
+    // `tid`, and any on earlier chromosomes.
+    pub fn drain_before(
+        &mut self,
+        tid: i32,
+        pos: i64,
+    ) -> Vec<(DedupKey, HashMap<Vec<u8>, UmiReads>)> {
+        match self.groups.first_key_value() {
+            Some((first, _)) if (first.tid, first.pos) < (tid, pos) => {}
+            _ => return vec![],
+        }
+        // The smallest possible key at `pos`
+        let boundary = DedupKey {
+            tid,
+            pos,
+            reverse: false,
+            second_in_pair: false,
+        };
+        let kept = self.groups.split_off(&boundary);
+        std::mem::replace(&mut self.groups, kept)
+            .into_iter()
+            .collect()
+    }
+
+    pub fn drain_all(&mut self) -> Vec<(DedupKey, HashMap<Vec<u8>, UmiReads>)> {
+        std::mem::take(&mut self.groups).into_iter().collect()
+    }
+}
+
+fn hamming_one(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).filter(|(x, y)| x != y).count() == 1
+}
+
+// The molecules represented by the UMIs at one key, as the UMI that
+// represents each of them.
+//
+// - Exact: every distinct UMI is a molecule.
+// - Cluster: UMIs connected by a Hamming distance of 1 are one molecule.
+// - Directional: as for Cluster, but UMI a only absorbs UMI b if
+//   count(a) >= 2 * count(b) - 1 (the UMI-tools directional method).
+//
+// The most frequent UMI of a cluster represents it.
+pub fn molecules(umis: &HashMap<Vec<u8>, usize>, method: UmiMethod) -> Vec<&Vec<u8>> {
+    let mut sorted: Vec<(&Vec<u8>, usize)> = umis.iter().map(|(u, c)| (u, *c)).collect();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    if method == UmiMethod::Exact {
+        return sorted.into_iter().map(|(umi, _)| umi).collect();
+    }
+    let connected = |from: usize, to: usize| {
+        let (a, count_a) = sorted[from];
+        let (b, count_b) = sorted[to];
+        hamming_one(a, b)
+            && match method {
+                UmiMethod::Directional => count_a + 1 >= 2 * count_b,
+                _ => true,
+            }
+    };
+
+    let mut assigned = vec![false; sorted.len()];
+    let mut roots = vec![];
+    for root in 0..sorted.len() {
+        if assigned[root] {
+            continue;
+        }
+        roots.push(sorted[root].0);
+        assigned[root] = true;
+        let mut queue = vec![root];
+        while let Some(node) = queue.pop() {
+            let reachable: Vec<usize> = (0..sorted.len())
+                .filter(|&next| !assigned[next] && connected(node, next))
+                .collect();
+            for next in reachable {
+                assigned[next] = true;
+                queue.push(next);
+            }
+        }
+    }
+    roots
+}
+
+// Adds each molecule of the UMI groups to the counts for the category and
+// filter outcome of its representative read.
+pub fn add_molecules(
+    counts: &mut CategoryCounts,
+    groups: Vec<(DedupKey, HashMap<Vec<u8>, UmiReads>)>,
+    method: UmiMethod,
+) {
+    for (_, umis) in groups {
+        let umi_counts: HashMap<Vec<u8>, usize> = umis
+            .iter()
+            .map(|(umi, reads)| (umi.clone(), reads.count))
+            .collect();
+        for umi in molecules(&umi_counts, method) {
+            let reads = &umis[umi];
+            let outcome = if reads.accepted {
+                ReadCheckOutcome::Accept
+            } else {
+                ReadCheckOutcome::Reject
+            };
+            counts.add(&reads.categories, &outcome);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn umis(counts: &[(&str, usize)]) -> HashMap<Vec<u8>, usize> {
+        counts
+            .iter()
+            .map(|(umi, count)| (umi.as_bytes().to_vec(), *count))
+            .collect()
+    }
+
+    fn key(pos: i64) -> DedupKey {
+        DedupKey {
+            tid: 0,
+            pos,
+            reverse: false,
+            second_in_pair: false,
+        }
+    }
+
+    fn count_molecules(umis: &HashMap<Vec<u8>, usize>, method: UmiMethod) -> usize {
+        molecules(umis, method).len()
+    }
+
+    #[test]
+    fn test_count_molecules_exact() {
+        let group = umis(&[("AAAA", 5), ("AAAT", 1), ("CCCC", 2)]);
+        assert_eq!(count_molecules(&group, UmiMethod::Exact), 3);
+    }
+
+    #[test]
+    fn test_count_molecules_cluster() {
+        // AAAA - AAAT - AATT form one chain; CCCC is separate
+        let group = umis(&[("AAAA", 5), ("AAAT", 5), ("AATT", 5), ("CCCC", 2)]);
+        assert_eq!(count_molecules(&group, UmiMethod::Cluster), 2);
+    }
+
+    #[test]
+    fn test_count_molecules_directional() {
+        // AAAT is absorbed by AAAA (5 >= 2 * 1 - 1), but AATT has too many
+        // reads to be an error of AAAT
+        let group = umis(&[("AAAA", 5), ("AAAT", 1), ("AATT", 4)]);
+        assert_eq!(count_molecules(&group, UmiMethod::Directional), 2);
+        assert_eq!(count_molecules(&group, UmiMethod::Cluster), 1);
+    }
+
+    #[test]
+    fn test_umi_groups_drain_before() {
+        let mut groups = UmiGroups::default();
+        let accept = ReadCheckOutcome::Accept;
+        groups.add(key(100), b"AAAA".to_vec(), &[0], &accept);
+        groups.add(key(100), b"AAAA".to_vec(), &[0], &accept);
+        groups.add(key(200), b"CCCC".to_vec(), &[0], &accept);
+        let drained = groups.drain_before(0, 150);
+        assert_eq!(drained.len(), 1);
+        assert_eq!(drained[0].1[&b"AAAA".to_vec()].count, 2);
+        assert_eq!(groups.drain_all().len(), 1);
+    }
+
+    #[test]
+    fn test_add_molecules_uses_representative() {
+        // Duplicates that differ in category or outcome are one molecule,
+        // counted once in the categories of the first accepted read
+        let labels: Vec<String> = ["Exon", "Intron", "Intergenic"]
+            .iter()
+            .map(|label| label.to_string())
+            .collect();
+        let mut groups = UmiGroups::default();
+        let reject = ReadCheckOutcome::Reject;
+        let accept = ReadCheckOutcome::Accept;
+        groups.add(key(100), b"AAAA".to_vec(), &[1], &reject);
+        groups.add(key(100), b"AAAA".to_vec(), &[0], &accept);
+        groups.add(key(100), b"AAAA".to_vec(), &[1], &accept);
+        groups.add(key(100), b"CCCC".to_vec(), &[2], &reject);
+        let mut counts = CategoryCounts::new(&labels);
+        add_molecules(&mut counts, groups.drain_all(), UmiMethod::Exact);
+        assert_eq!(counts.get("Exon").unwrap().accepted, 1);
+        assert_eq!(counts.get("Intron").unwrap().total(), 0);
+        assert_eq!(counts.get("Intergenic").unwrap().rejected, 1);
+        assert_eq!(counts.mapped().accepted, 1);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::cigar::{cigar_aligned_length, cigar_soft_clip_length};
+use crate::FLAG_PROPER_PAIR;
+use rust_htslib::bam::Record;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// A sparse histogram of integer values, keyed by value.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct Histogram {
+    counts: BTreeMap<i64, usize>,
+}
+
+impl Histogram {
+    pub fn add(&mut self, value: i64) {
+        *self.counts.entry(value).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (value, count) in &other.counts {
+            *self.counts.entry(*value).or_insert(0) += count;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (i64, usize)> + '_ {
+        self.counts.iter().map(|(value, count)| (*value, *count))
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        let sum: f64 = self.iter().map(|(v, c)| v as f64 * c as f64).sum();
+        Some(sum / total as f64)
+    }
+
+    // Returns the smallest value at which the cumulative count reaches
+    // half of the total.
+    pub fn median(&self) -> Option<i64> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        let half = total.div_ceil(2);
+        let mut cumulative = 0;
+        for (value, count) in self.iter() {
+            cumulative += count;
+            if cumulative >= half {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+// Histograms collected for one class (accepted or rejected) of reads.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReadDistributions {
+    pub template_length: Histogram,
+    pub read_length: Histogram,
+    pub soft_clip_length: Histogram,
+    pub mapq: Histogram,
+    // Percentage of aligned bases falling in exons, only collected in
+    // long-read mode
+    #[serde(skip_serializing_if = "Histogram::is_empty")]
+    pub exon_fraction: Histogram,
+}
+
+impl ReadDistributions {
+    // Template length is only recorded for proper pairs, and only from the
+    // leftmost mate (positive TLEN) so that each template is counted once.
+    pub fn add(&mut self, read: &Record) {
+        if read.flags() & FLAG_PROPER_PAIR != 0 && read.insert_size() > 0 {
+            self.template_length.add(read.insert_size());
+        }
+        self.read_length.add(cigar_aligned_length(read));
+        self.soft_clip_length.add(cigar_soft_clip_length(read));
+        self.mapq.add(read.mapq() as i64);
+    }
+
+    pub fn merge(&mut self, other: &ReadDistributions) {
+        self.template_length.merge(&other.template_length);
+        self.read_length.merge(&other.read_length);
+        self.soft_clip_length.merge(&other.soft_clip_length);
+        self.mapq.merge(&other.mapq);
+        self.exon_fraction.merge(&other.exon_fraction);
+    }
+
+    pub fn named(&self) -> Vec<(&'static str, &Histogram)> {
+        let mut named = vec![
+            ("TemplateLength", &self.template_length),
+            ("ReadLength", &self.read_length),
+            ("SoftClipLength", &self.soft_clip_length),
+            ("MappingQuality", &self.mapq),
+        ];
+        if !self.exon_fraction.is_empty() {
+            named.push(("ExonFractionPercent", &self.exon_fraction));
+        }
+        named
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DistributionSet {
+    pub accepted: ReadDistributions,
+    pub rejected: ReadDistributions,
+}
+
+impl DistributionSet {
+    pub fn merge(&mut self, other: &DistributionSet) {
+        self.accepted.merge(&other.accepted);
+        self.rejected.merge(&other.rejected);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_add_and_total() {
+        let mut hist = Histogram::default();
+        hist.add(10);
+        hist.add(10);
+        hist.add(20);
+        assert_eq!(hist.total(), 3);
+        assert_eq!(hist.iter().collect::<Vec<_>>(), vec![(10, 2), (20, 1)]);
+    }
+
+    #[test]
+    fn test_histogram_merge() {
+        let mut a = Histogram::default();
+        a.add(1);
+        a.add(2);
+        let mut b = Histogram::default();
+        b.add(2);
+        b.add(3);
+        a.merge(&b);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![(1, 1), (2, 2), (3, 1)]);
+    }
+
+    #[test]
+    fn test_histogram_mean_and_median() {
+        let mut hist = Histogram::default();
+        assert_eq!(hist.mean(), None);
+        assert_eq!(hist.median(), None);
+        for value in [100, 200, 200, 500] {
+            hist.add(value);
+        }
+        assert_eq!(hist.mean(), Some(250.0));
+        assert_eq!(hist.median(), Some(200));
+    }
+}
@@ -0,0 +1,138 @@
+use crate::cigar::cigar_end_pos;
+use rust_htslib::bam::Record;
+use std::collections::{BTreeSet, HashSet};
+
+const FLAG_PAIRED: u16 = 1;
+
+// Reads with the same fragment signature are assumed to be duplicates when
+// the BAM file has not been duplicate-marked. For paired reads the fragment
+// spans both mates and takes the strand of the first mate; for single reads
+// it is the aligned span of the read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FragmentKey {
+    pub tid: i32,
+    pub start: i64,
+    pub end: i64,
+    pub reverse: bool,
+    pub second_in_pair: bool,
+}
+
+impl FragmentKey {
+    pub fn from_read(read: &Record) -> Self {
+        if read.flags() & FLAG_PAIRED != 0 && read.insert_size() != 0 {
+            let start = read.pos().min(read.mpos());
+            let reverse = if read.is_last_in_template() {
+                read.is_mate_reverse()
+            } else {
+                read.is_reverse()
+            };
+            FragmentKey {
+                tid: read.tid(),
+                start,
+                end: start + read.insert_size().abs(),
+                reverse,
+                second_in_pair: read.is_last_in_template(),
+            }
+        } else {
+            FragmentKey {
+                tid: read.tid(),
+                start: read.pos(),
+                end: cigar_end_pos(read),
+                reverse: read.is_reverse(),
+                second_in_pair: false,
+            }
+        }
+    }
+
+    // The smallest possible key starting at `start` on chromosome `tid`
+    pub fn first_at(tid: i32, start: i64) -> Self {
+        FragmentKey {
+            tid,
+            start,
+            end: i64::MIN,
+            reverse: false,
+            second_in_pair: false,
+        }
+    }
+}
+
+// Fragment signatures seen so far on one chromosome. Signatures of
+// fragments that end well before the current read can be forgotten to bound
+// memory use; a pair stays until its last mate has been seen, however long
+// its insert.
+#[derive(Debug, Default)]
+pub struct FragmentTracker {
+    seen: HashSet<FragmentKey>,
+    by_end: BTreeSet<(i32, i64, FragmentKey)>,
+}
+
+impl FragmentTracker {
+    // Returns true if a read with the same signature has already been seen
+    pub fn is_duplicate(&mut self, key: FragmentKey) -> bool {
+        if !self.seen.insert(key) {
+            return true;
+        }
+        self.by_end.insert((key.tid, key.end, key));
+        false
+    }
+
+    // Forgets the fragments that end before position `pos` on chromosome
+    // `tid`, and any on earlier chromosomes
+    pub fn forget_before(&mut self, tid: i32, pos: i64) {
+        let boundary = (tid, pos, FragmentKey::first_at(i32::MIN, i64::MIN));
+        match self.by_end.first() {
+            Some(first) if *first < boundary => {}
+            _ => return,
+        }
+        let kept = self.by_end.split_off(&boundary);
+        for (_, _, key) in std::mem::replace(&mut self.by_end, kept) {
+            self.seen.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(start: i64, end: i64, reverse: bool) -> FragmentKey {
+        FragmentKey {
+            tid: 0,
+            start,
+            end,
+            reverse,
+            second_in_pair: false,
+        }
+    }
+
+    #[test]
+    fn test_fragment_tracker_is_duplicate() {
+        let mut tracker = FragmentTracker::default();
+        assert!(!tracker.is_duplicate(key(100, 300, false)));
+        assert!(tracker.is_duplicate(key(100, 300, false)));
+        assert!(!tracker.is_duplicate(key(100, 300, true)));
+        assert!(!tracker.is_duplicate(key(100, 301, false)));
+    }
+
+    #[test]
+    fn test_fragment_tracker_forget_before() {
+        let mut tracker = FragmentTracker::default();
+        tracker.is_duplicate(key(100, 300, false));
+        tracker.is_duplicate(key(200, 400, false));
+        tracker.forget_before(0, 350);
+        assert!(!tracker.is_duplicate(key(100, 300, false)));
+        assert!(tracker.is_duplicate(key(200, 400, false)));
+    }
+
+    #[test]
+    fn test_fragment_tracker_keeps_long_inserts() {
+        // The second mates of a pair with a long insert are seen far from
+        // the fragment start, and must still find each other
+        let mut tracker = FragmentTracker::default();
+        assert!(!tracker.is_duplicate(key(100, 500_000, false)));
+        tracker.forget_before(0, 400_000);
+        assert!(tracker.is_duplicate(key(100, 500_000, false)));
+        tracker.forget_before(0, 500_001);
+        assert!(!tracker.is_duplicate(key(100, 500_000, false)));
+    }
+}
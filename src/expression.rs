@@ -0,0 +1,485 @@
+use crate::cigar::cigar_end_pos;
+use anyhow::Error;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::Record;
+
+// A read filter expression such as `[NM] <= 5 && !has_tag(SA)`, parsed once
+// and evaluated for each read. Operators, from lowest to highest precedence:
+//
+//     ||   &&   == != in   < <= > >=   &   + -   * /   ! - (unary)
+//
+// Operands are numbers, "strings", aux tags in brackets (`[AS]`), read
+// fields (mapq, flag, tlen, pos, mpos, qlen, rlen, qname) and the functions
+// has_tag(XX) and abs(x). A missing tag has no value, which makes any
+// comparison with it false except `!=`, which is true.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    text: String,
+    root: Node,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(f64),
+    Str(String),
+    Tag([u8; 2]),
+    Field(Field),
+    HasTag([u8; 2]),
+    Abs(Box<Node>),
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(Op, Box<Node>, Box<Node>),
+    In(Box<Node>, Vec<Node>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Mapq,
+    Flag,
+    Tlen,
+    Pos,
+    Mpos,
+    Qlen,
+    Rlen,
+    Qname,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitAnd,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    Str(String),
+    Missing,
+}
+
+impl Value {
+    fn is_true(&self) -> bool {
+        match self {
+            Value::Number(n) => *n != 0.0,
+            Value::Str(s) => !s.is_empty(),
+            Value::Missing => false,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Number(if b { 1.0 } else { 0.0 })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Str(String),
+    Ident(String),
+    Tag([u8; 2]),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 17] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "&", "+", "-", "*", "/", "!", "(", ")", ",",
+];
+
+fn tag_name(name: &str) -> Result<[u8; 2], Error> {
+    match name.as_bytes() {
+        [a, b] => Ok([*a, *b]),
+        _ => Err(Error::msg(format!("Invalid tag name: {}", name))),
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = vec![];
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_digit() || c == '.' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.'))
+                .unwrap_or(rest.len());
+            let number = rest[..len]
+                .parse()
+                .map_err(|_| Error::msg(format!("Invalid number: {}", &rest[..len])))?;
+            tokens.push(Token::Number(number));
+            rest = &rest[len..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            rest = &rest[len..];
+        } else if c == '"' {
+            let end = rest[1..]
+                .find('"')
+                .ok_or_else(|| Error::msg("Unterminated string"))?;
+            tokens.push(Token::Str(rest[1..end + 1].to_string()));
+            rest = &rest[end + 2..];
+        } else if c == '[' {
+            let end = rest
+                .find(']')
+                .ok_or_else(|| Error::msg("Unterminated tag"))?;
+            tokens.push(Token::Tag(tag_name(rest[1..end].trim())?));
+            rest = &rest[end + 1..];
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| rest.starts_with(**s))
+                .ok_or_else(|| Error::msg(format!("Unexpected character: {}", c)))?;
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn take_symbol(&mut self, symbols: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Symbol(s)) if symbols.contains(s) => {
+                let s = *s;
+                self.next += 1;
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), Error> {
+        self.take_symbol(&[symbol])
+            .map(|_| ())
+            .ok_or_else(|| Error::msg(format!("Expected '{}'", symbol)))
+    }
+
+    // Parses a left-associative chain of the operators at one precedence
+    // level, with operands parsed by `operand`
+    fn binary(
+        &mut self,
+        ops: &[(&str, Op)],
+        operand: fn(&mut Parser) -> Result<Node, Error>,
+    ) -> Result<Node, Error> {
+        let mut node = operand(self)?;
+        let symbols: Vec<&str> = ops.iter().map(|(s, _)| *s).collect();
+        while let Some(symbol) = self.take_symbol(&symbols) {
+            let op = ops.iter().find(|(s, _)| *s == symbol).unwrap().1;
+            node = Node::Binary(op, Box::new(node), Box::new(operand(self)?));
+        }
+        Ok(node)
+    }
+
+    fn or(&mut self) -> Result<Node, Error> {
+        self.binary(&[("||", Op::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Node, Error> {
+        self.binary(&[("&&", Op::And)], Parser::equality)
+    }
+
+    fn equality(&mut self) -> Result<Node, Error> {
+        let node = self.binary(&[("==", Op::Eq), ("!=", Op::Ne)], Parser::comparison)?;
+        if matches!(self.peek(), Some(Token::Ident(name)) if name == "in") {
+            self.next += 1;
+            self.expect("(")?;
+            let mut values = vec![self.comparison()?];
+            while self.take_symbol(&[","]).is_some() {
+                values.push(self.comparison()?);
+            }
+            self.expect(")")?;
+            return Ok(Node::In(Box::new(node), values));
+        }
+        Ok(node)
+    }
+
+    fn comparison(&mut self) -> Result<Node, Error> {
+        self.binary(
+            &[("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)],
+            Parser::bitwise,
+        )
+    }
+
+    fn bitwise(&mut self) -> Result<Node, Error> {
+        self.binary(&[("&", Op::BitAnd)], Parser::sum)
+    }
+
+    fn sum(&mut self) -> Result<Node, Error> {
+        self.binary(&[("+", Op::Add), ("-", Op::Sub)], Parser::product)
+    }
+
+    fn product(&mut self) -> Result<Node, Error> {
+        self.binary(&[("*", Op::Mul), ("/", Op::Div)], Parser::unary)
+    }
+
+    fn unary(&mut self) -> Result<Node, Error> {
+        match self.take_symbol(&["!", "-"]) {
+            Some("!") => Ok(Node::Not(Box::new(self.unary()?))),
+            Some(_) => Ok(Node::Negate(Box::new(self.unary()?))),
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, Error> {
+        let token = self
+            .tokens
+            .get(self.next)
+            .cloned()
+            .ok_or_else(|| Error::msg("Unexpected end of expression"))?;
+        self.next += 1;
+        match token {
+            Token::Number(n) => Ok(Node::Number(n)),
+            Token::Str(s) => Ok(Node::Str(s)),
+            Token::Tag(tag) => Ok(Node::Tag(tag)),
+            Token::Symbol("(") => {
+                let node = self.or()?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Token::Ident(name) if self.take_symbol(&["("]).is_some() => {
+                let node = match name.as_str() {
+                    "has_tag" => {
+                        let tag = match self.tokens.get(self.next).cloned() {
+                            Some(Token::Ident(tag)) | Some(Token::Str(tag)) => tag_name(&tag)?,
+                            Some(Token::Tag(tag)) => tag,
+                            _ => return Err(Error::msg("has_tag expects a tag name")),
+                        };
+                        self.next += 1;
+                        Node::HasTag(tag)
+                    }
+                    "abs" => Node::Abs(Box::new(self.or()?)),
+                    _ => return Err(Error::msg(format!("Unknown function: {}", name))),
+                };
+                self.expect(")")?;
+                Ok(node)
+            }
+            Token::Ident(name) => {
+                let field = match name.as_str() {
+                    "mapq" => Field::Mapq,
+                    "flag" => Field::Flag,
+                    "tlen" => Field::Tlen,
+                    "pos" => Field::Pos,
+                    "mpos" => Field::Mpos,
+                    "qlen" => Field::Qlen,
+                    "rlen" => Field::Rlen,
+                    "qname" => Field::Qname,
+                    _ => return Err(Error::msg(format!("Unknown field: {}", name))),
+                };
+                Ok(Node::Field(field))
+            }
+            Token::Symbol(s) => Err(Error::msg(format!("Unexpected '{}'", s))),
+        }
+    }
+}
+
+fn aux_value(read: &Record, tag: &[u8; 2]) -> Value {
+    match read.aux(tag) {
+        Ok(Aux::Char(c)) => Value::Str((c as char).to_string()),
+        Ok(Aux::I8(v)) => Value::Number(v as f64),
+        Ok(Aux::U8(v)) => Value::Number(v as f64),
+        Ok(Aux::I16(v)) => Value::Number(v as f64),
+        Ok(Aux::U16(v)) => Value::Number(v as f64),
+        Ok(Aux::I32(v)) => Value::Number(v as f64),
+        Ok(Aux::U32(v)) => Value::Number(v as f64),
+        Ok(Aux::Float(v)) => Value::Number(v as f64),
+        Ok(Aux::Double(v)) => Value::Number(v),
+        Ok(Aux::String(s)) => Value::Str(s.to_string()),
+        _ => Value::Missing,
+    }
+}
+
+fn field_value(read: &Record, field: Field) -> Value {
+    match field {
+        Field::Mapq => Value::Number(read.mapq() as f64),
+        Field::Flag => Value::Number(read.flags() as f64),
+        Field::Tlen => Value::Number(read.insert_size() as f64),
+        Field::Pos => Value::Number((read.pos() + 1) as f64),
+        Field::Mpos => Value::Number((read.mpos() + 1) as f64),
+        Field::Qlen => Value::Number(read.seq_len() as f64),
+        Field::Rlen => Value::Number((cigar_end_pos(read) - read.pos()) as f64),
+        Field::Qname => Value::Str(String::from_utf8_lossy(read.qname()).to_string()),
+    }
+}
+
+fn compare(op: Op, left: &Value, right: &Value) -> Value {
+    let ordering = match (left, right) {
+        (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+        (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+        _ => None,
+    };
+    let Some(ordering) = ordering else {
+        return Value::from(op == Op::Ne && left != right);
+    };
+    Value::from(match op {
+        Op::Eq => ordering.is_eq(),
+        Op::Ne => ordering.is_ne(),
+        Op::Lt => ordering.is_lt(),
+        Op::Le => ordering.is_le(),
+        Op::Gt => ordering.is_gt(),
+        _ => ordering.is_ge(),
+    })
+}
+
+fn evaluate(node: &Node, read: &Record) -> Value {
+    match node {
+        Node::Number(n) => Value::Number(*n),
+        Node::Str(s) => Value::Str(s.clone()),
+        Node::Tag(tag) => aux_value(read, tag),
+        Node::Field(field) => field_value(read, *field),
+        Node::HasTag(tag) => Value::from(read.aux(tag).is_ok()),
+        Node::Abs(inner) => match evaluate(inner, read) {
+            Value::Number(n) => Value::Number(n.abs()),
+            _ => Value::Missing,
+        },
+        Node::Not(inner) => Value::from(!evaluate(inner, read).is_true()),
+        Node::Negate(inner) => match evaluate(inner, read) {
+            Value::Number(n) => Value::Number(-n),
+            _ => Value::Missing,
+        },
+        Node::In(inner, values) => {
+            let value = evaluate(inner, read);
+            Value::from(
+                value != Value::Missing && values.iter().any(|v| evaluate(v, read) == value),
+            )
+        }
+        Node::Binary(Op::Or, left, right) => {
+            Value::from(evaluate(left, read).is_true() || evaluate(right, read).is_true())
+        }
+        Node::Binary(Op::And, left, right) => {
+            Value::from(evaluate(left, read).is_true() && evaluate(right, read).is_true())
+        }
+        Node::Binary(op, left, right) => {
+            let (left, right) = (evaluate(left, read), evaluate(right, read));
+            match op {
+                Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge => compare(*op, &left, &right),
+                _ => match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => Value::Number(match op {
+                        Op::BitAnd => ((a as i64) & (b as i64)) as f64,
+                        Op::Add => a + b,
+                        Op::Sub => a - b,
+                        Op::Mul => a * b,
+                        _ => a / b,
+                    }),
+                    _ => Value::Missing,
+                },
+            }
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            next: 0,
+        };
+        let root = parser
+            .or()
+            .and_then(|root| match parser.peek() {
+                None => Ok(root),
+                Some(token) => Err(Error::msg(format!("Unexpected {:?}", token))),
+            })
+            .map_err(|e| Error::msg(format!("Invalid filter expression `{}`: {}", text, e)))?;
+        Ok(Expression {
+            text: text.to_string(),
+            root,
+        })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // True if the read passes the filter
+    pub fn matches(&self, read: &Record) -> bool {
+        evaluate(&self.root, read).is_true()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    fn record() -> Record {
+        let mut read = Record::new();
+        let cigar = CigarString(vec![Cigar::Match(50), Cigar::SoftClip(10)]);
+        read.set(b"read1", Some(&cigar), &[b'A'; 60], &[30; 60]);
+        read.set_pos(999);
+        read.set_mapq(60);
+        read.set_flags(99);
+        read.set_insert_size(350);
+        read.push_aux(b"NM", Aux::U8(3)).unwrap();
+        read.push_aux(b"AS", Aux::I32(95)).unwrap();
+        read.push_aux(b"XS", Aux::I32(80)).unwrap();
+        read.push_aux(b"RG", Aux::String("lane1")).unwrap();
+        read
+    }
+
+    fn matches(text: &str) -> bool {
+        Expression::parse(text).unwrap().matches(&record())
+    }
+
+    #[test]
+    fn test_expression_tags_and_fields() {
+        assert!(matches("[NM] <= 5"));
+        assert!(!matches("[NM] > 5"));
+        assert!(matches("[AS] - [XS] > 10"));
+        assert!(!matches("[AS] - [XS] > 20"));
+        assert!(matches("tlen < 1000 && mapq >= 30"));
+        assert!(matches("pos == 1000 && qlen == 60 && rlen == 50"));
+        assert!(matches("flag & 64"));
+        assert!(!matches("flag & 16"));
+        assert!(matches("qname == \"read1\""));
+    }
+
+    #[test]
+    fn test_expression_missing_tags() {
+        assert!(matches("!has_tag(SA)"));
+        assert!(matches("has_tag([NM])"));
+        assert!(!matches("[XA] < 5"));
+        assert!(!matches("[XA] >= 5"));
+        assert!(matches("[XA] != 5"));
+    }
+
+    #[test]
+    fn test_expression_in_set_and_precedence() {
+        assert!(matches("[RG] in (\"lane1\", \"lane2\")"));
+        assert!(!matches("[RG] in (\"lane3\")"));
+        assert!(matches("1 + 2 * 3 == 7"));
+        assert!(matches("(1 + 2) * 3 == 9"));
+        assert!(matches("0 || 1 && 1"));
+        assert!(matches("abs(-tlen) == 350"));
+    }
+
+    #[test]
+    fn test_expression_parse_errors() {
+        assert!(Expression::parse("[NM] <=").is_err());
+        assert!(Expression::parse("foo > 1").is_err());
+        assert!(Expression::parse("(mapq > 1").is_err());
+        assert!(Expression::parse("mapq > 1 )").is_err());
+        assert!(Expression::parse("[NMX] > 1").is_err());
+    }
+}
@@ -0,0 +1,308 @@
+use crate::annotate::part_path;
+use crate::classify::RegionCategory;
+use crate::ReadCheckOutcome;
+use anyhow::Error;
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rust_htslib::bam::{Format, Header, HeaderView, Read, Reader, Record, Writer};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Reads that can be extracted with --extract. Exon, Intron, Intergenic and
+// Unmapped are the accepted reads of that category; Rejected are the mapped
+// and unmapped reads rejected by the filters.
+#[derive(ValueEnum, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExtractCategory {
+    Exon,
+    Intron,
+    Intergenic,
+    Unmapped,
+    Rejected,
+}
+
+impl From<RegionCategory> for ExtractCategory {
+    fn from(category: RegionCategory) -> Self {
+        match category {
+            RegionCategory::Exon => ExtractCategory::Exon,
+            RegionCategory::Intron => ExtractCategory::Intron,
+            RegionCategory::Intergenic => ExtractCategory::Intergenic,
+        }
+    }
+}
+
+// Whether a read of category `category` (None for reads, such as spike-ins,
+// that have none) is to be extracted
+pub fn is_extracted(
+    extract: &[ExtractCategory],
+    category: Option<ExtractCategory>,
+    outcome: ReadCheckOutcome,
+) -> bool {
+    match outcome {
+        ReadCheckOutcome::Reject => extract.contains(&ExtractCategory::Rejected),
+        ReadCheckOutcome::Accept => category.is_some_and(|c| extract.contains(&c)),
+    }
+}
+
+fn is_fastq(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    name.ends_with(".fastq") || name.ends_with(".fq")
+}
+
+// Output whose parts hold the split long reads extracted after counting
+pub fn split_read_parts(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".split");
+    PathBuf::from(path)
+}
+
+fn next_read(reader: &mut Reader) -> Result<Option<Record>, Error> {
+    let mut read = Record::new();
+    match reader.read(&mut read) {
+        Some(result) => {
+            result?;
+            Ok(Some(read))
+        }
+        None => Ok(None),
+    }
+}
+
+// Passes the reads of the parts that were written, in order, to `f` and
+// removes the parts. The reads of each part are merged by position with
+// those of the split long reads extracted for it.
+fn read_extracted_parts(
+    output: &Path,
+    parts: usize,
+    mut f: impl FnMut(&Record) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let split = split_read_parts(output);
+    for part in 0..parts {
+        let mut sources = vec![];
+        for path in [part_path(output, part), part_path(&split, part)] {
+            if path.exists() {
+                let mut reader = Reader::from_path(&path)?;
+                let next = next_read(&mut reader)?;
+                sources.push((reader, next, path));
+            }
+        }
+        while let Some((_, i)) = sources
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, next, _))| Some((next.as_ref()?.pos(), i)))
+            .min()
+        {
+            let (reader, next, _) = &mut sources[i];
+            if let Some(read) = next.take() {
+                f(&read)?;
+            }
+            *next = next_read(reader)?;
+        }
+        for (_, _, path) in sources {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+// Writes the extracted parts to the output: as they are for a BAM file, or
+// as FASTQ if its name ends in .fastq or .fq, optionally followed by .gz
+pub fn write_extracted(output: &Path, parts: usize, header: &HeaderView) -> Result<(), Error> {
+    if !is_fastq(output) {
+        let mut writer = Writer::from_path(output, &Header::from_template(header), Format::Bam)?;
+        return read_extracted_parts(output, parts, |read| Ok(writer.write(read)?));
+    }
+    let file = File::create(output)?;
+    let mut out: Box<dyn Write> = if output.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(BufWriter::new(GzEncoder::new(file, Compression::default())))
+    } else {
+        Box::new(BufWriter::new(file))
+    };
+    write_fastq(&mut out, |f| read_extracted_parts(output, parts, f))?;
+    out.flush()?;
+    Ok(())
+}
+
+// Position in the order of the extracted reads, which are sorted by
+// position with the unplaced reads last
+fn sort_position(tid: i32, pos: i64) -> (i32, i64) {
+    if tid < 0 {
+        (i32::MAX, pos)
+    } else {
+        (tid, pos)
+    }
+}
+
+// Writes reads as FASTQ in their original orientation. Mates are written
+// together, first mate first, with /1 and /2 suffixes, once both have been
+// seen. A read whose mate was not extracted is written unsuffixed once the
+// reads have passed the position of its mate.
+fn write_fastq(
+    out: &mut impl Write,
+    reads: impl FnOnce(&mut dyn FnMut(&Record) -> Result<(), Error>) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut waiting: HashMap<Vec<u8>, Record> = HashMap::new();
+    let mut by_mate_pos: BTreeSet<((i32, i64), Vec<u8>)> = BTreeSet::new();
+    reads(&mut |read: &Record| {
+        let current = sort_position(read.tid(), read.pos());
+        let kept = by_mate_pos.split_off(&(current, vec![]));
+        for (_, qname) in std::mem::replace(&mut by_mate_pos, kept) {
+            if let Some(alone) = waiting.remove(&qname) {
+                write_fastq_record(out, &alone, "")?;
+            }
+        }
+        if !read.is_paired() {
+            return write_fastq_record(out, read, "");
+        }
+        match waiting.remove(read.qname()) {
+            Some(mate) => {
+                let (first, second) = if mate.is_first_in_template() {
+                    (&mate, read)
+                } else {
+                    (read, &mate)
+                };
+                write_fastq_record(out, first, "/1")?;
+                write_fastq_record(out, second, "/2")
+            }
+            None => {
+                let mate_pos = sort_position(read.mtid(), read.mpos());
+                // The mate was written before this read, alone
+                if mate_pos < current {
+                    return write_fastq_record(out, read, "");
+                }
+                by_mate_pos.insert((mate_pos, read.qname().to_vec()));
+                waiting.insert(read.qname().to_vec(), read.clone());
+                Ok(())
+            }
+        }
+    })?;
+    for (_, qname) in by_mate_pos {
+        if let Some(read) = waiting.remove(&qname) {
+            write_fastq_record(out, &read, "")?;
+        }
+    }
+    Ok(())
+}
+
+fn write_fastq_record(out: &mut impl Write, read: &Record, suffix: &str) -> Result<(), Error> {
+    let mut seq = read.seq().as_bytes();
+    let mut qual: Vec<u8> = read
+        .qual()
+        .iter()
+        .map(|&q| {
+            if q == 255 {
+                b'!'
+            } else {
+                q.saturating_add(33).min(b'~')
+            }
+        })
+        .collect();
+    if read.is_reverse() {
+        seq = seq
+            .iter()
+            .rev()
+            .map(|base| match base {
+                b'A' => b'T',
+                b'C' => b'G',
+                b'G' => b'C',
+                b'T' => b'A',
+                other => *other,
+            })
+            .collect();
+        qual.reverse();
+    }
+    out.write_all(b"@")?;
+    out.write_all(read.qname())?;
+    out.write_all(suffix.as_bytes())?;
+    out.write_all(b"\n")?;
+    out.write_all(&seq)?;
+    out.write_all(b"\n+\n")?;
+    out.write_all(&qual)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(qname: &[u8], seq: &[u8], flags: u16) -> Record {
+        let mut read = Record::new();
+        read.set(qname, None, seq, &vec![30; seq.len()]);
+        read.set_flags(flags);
+        read
+    }
+
+    #[test]
+    fn test_is_extracted() {
+        let extract = [ExtractCategory::Intergenic, ExtractCategory::Rejected];
+        let intergenic = Some(RegionCategory::Intergenic.into());
+        let exon = Some(RegionCategory::Exon.into());
+        assert!(is_extracted(&extract, intergenic, ReadCheckOutcome::Accept));
+        assert!(!is_extracted(&extract, exon, ReadCheckOutcome::Accept));
+        assert!(is_extracted(&extract, exon, ReadCheckOutcome::Reject));
+        assert!(!is_extracted(&extract, None, ReadCheckOutcome::Accept));
+        assert!(is_fastq(Path::new("out/reads.fq.gz")));
+        assert!(!is_fastq(Path::new("reads.bam")));
+    }
+
+    #[test]
+    fn test_write_fastq() {
+        let reads = [
+            // Second mate seen first, reverse strand
+            record(b"pair", b"AACG", 1 | 16 | 128),
+            record(b"single", b"GGTT", 0),
+            record(b"orphan", b"ACGT", 1 | 64),
+            record(b"pair", b"TTTT", 1 | 64),
+        ];
+        let mut out = vec![];
+        write_fastq(&mut out, |f| reads.iter().try_for_each(f)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "@single\nGGTT\n+\n????\n\
+             @pair/1\nTTTT\n+\n????\n\
+             @pair/2\nCGTT\n+\n????\n\
+             @orphan\nACGT\n+\n????\n"
+        );
+    }
+
+    #[test]
+    fn test_write_fastq_unpaired_mate_by_position() {
+        let placed = |qname: &[u8], seq: &[u8], flags: u16, pos: i64, mate_pos: i64| {
+            let mut read = record(qname, seq, flags);
+            read.set_tid(0);
+            read.set_pos(pos);
+            read.set_mtid(0);
+            read.set_mpos(mate_pos);
+            read
+        };
+        let mut high = Record::new();
+        high.set(b"high", None, b"AC", &[93, 250]);
+        high.set_tid(0);
+        high.set_pos(400);
+        let reads = [
+            // The mate at 200 is not extracted, so the read is written once
+            // the reads pass 200
+            placed(b"orphan", b"ACGT", 1 | 64, 100, 200),
+            placed(b"pair", b"GGGG", 1 | 64, 150, 300),
+            placed(b"late", b"TTTT", 1 | 128, 250, 100),
+            placed(b"pair", b"CCCC", 1 | 128, 300, 150),
+            // Qualities beyond the range of FASTQ are capped
+            high,
+        ];
+        let mut out = vec![];
+        write_fastq(&mut out, |f| reads.iter().try_for_each(f)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "@orphan\nACGT\n+\n????\n\
+             @late\nTTTT\n+\n????\n\
+             @pair/1\nGGGG\n+\n????\n\
+             @pair/2\nCCCC\n+\n????\n\
+             @high\nAC\n+\n~~\n"
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use crate::classify::IntervalSet;
+use crate::transcripts::Transcript;
+use anyhow::Error;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+
+// An annotated intron: the gap between two consecutive exons of at least
+// one transcript
+#[derive(Debug, Clone)]
+pub struct Intron {
+    pub seqname: String,
+    pub start: i64,
+    pub end: i64,
+    pub gene_ids: Vec<String>,
+}
+
+// Accepted reads supporting the splicing or the retention of an intron
+#[derive(Debug, Clone, Default)]
+pub struct IntronCounts {
+    // Reads with a splice junction exactly matching the intron
+    pub spliced: usize,
+    // Unspliced reads crossing the exon-intron boundary at the start or the
+    // intron-exon boundary at the end
+    pub start_boundary: usize,
+    pub end_boundary: usize,
+    // Reads aligned entirely within the intron
+    pub intronic: usize,
+}
+
+impl IntronCounts {
+    pub fn merge(&mut self, other: &IntronCounts) {
+        self.spliced += other.spliced;
+        self.start_boundary += other.start_boundary;
+        self.end_boundary += other.end_boundary;
+        self.intronic += other.intronic;
+    }
+
+    // Boundary-spanning reads, averaged over both boundaries as a retained
+    // intron gives reads at each, over those plus spliced reads. None
+    // without either.
+    pub fn retention_ratio(&self) -> Option<f64> {
+        let retained = (self.start_boundary + self.end_boundary) as f64 / 2.0;
+        let total = retained + self.spliced as f64;
+        (total > 0.0).then(|| retained / total)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IntronIndex {
+    introns: Vec<Intron>,
+    intervals: HashMap<String, IntervalSet>,
+}
+
+impl IntronIndex {
+    // Introns of all transcripts, each intron once, sorted by position
+    pub fn new(transcripts: &[Transcript]) -> Self {
+        let mut genes: BTreeMap<(&str, i64, i64), BTreeSet<&str>> = BTreeMap::new();
+        for transcript in transcripts {
+            for pair in transcript.exons.windows(2) {
+                genes
+                    .entry((&transcript.seqname, pair[0].1, pair[1].0))
+                    .or_default()
+                    .insert(&transcript.gene_id);
+            }
+        }
+        let introns: Vec<Intron> = genes
+            .into_iter()
+            .map(|((seqname, start, end), gene_ids)| Intron {
+                seqname: seqname.to_string(),
+                start,
+                end,
+                gene_ids: gene_ids.into_iter().map(|id| id.to_string()).collect(),
+            })
+            .collect();
+        let mut by_chrom: HashMap<String, Vec<(i64, i64, usize)>> = HashMap::new();
+        for (i, intron) in introns.iter().enumerate() {
+            by_chrom
+                .entry(intron.seqname.clone())
+                .or_default()
+                .push((intron.start, intron.end, i));
+        }
+        IntronIndex {
+            introns,
+            intervals: by_chrom
+                .into_iter()
+                .map(|(chrom, intervals)| (chrom, IntervalSet::new(intervals)))
+                .collect(),
+        }
+    }
+
+    pub fn introns(&self) -> &[Intron] {
+        &self.introns
+    }
+
+    // Adds a read on `chrom`, aligned in `segments` split at its splice
+    // junctions, to the counts of the introns it overlaps or splices out
+    pub fn count(
+        &self,
+        chrom: &str,
+        segments: &[(i64, i64)],
+        counts: &mut HashMap<usize, IntronCounts>,
+    ) {
+        let (Some(intervals), Some(first), Some(last)) =
+            (self.intervals.get(chrom), segments.first(), segments.last())
+        else {
+            return;
+        };
+        // A spliced read overlaps the introns it skips only through its gap
+        let mut candidates = vec![];
+        intervals.overlapping(&[(first.0, last.1)], &mut candidates);
+        for i in candidates {
+            let intron = &self.introns[i];
+            let spliced = segments
+                .windows(2)
+                .any(|pair| pair[0].1 == intron.start && pair[1].0 == intron.end);
+            let crosses = |boundary: i64| {
+                segments
+                    .iter()
+                    .any(|&(start, end)| start < boundary && boundary < end)
+            };
+            let start_boundary = crosses(intron.start);
+            let end_boundary = crosses(intron.end);
+            let intronic = first.0 >= intron.start && last.1 <= intron.end;
+            if !(spliced || start_boundary || end_boundary || intronic) {
+                continue;
+            }
+            let counts = counts.entry(i).or_default();
+            counts.spliced += spliced as usize;
+            counts.start_boundary += start_boundary as usize;
+            counts.end_boundary += end_boundary as usize;
+            counts.intronic += intronic as usize;
+        }
+    }
+}
+
+// Accepted reads of each intron, by index into `introns`
+#[derive(Debug, Clone)]
+pub struct IntronTable {
+    pub introns: Vec<Intron>,
+    pub counts: HashMap<usize, IntronCounts>,
+}
+
+impl IntronTable {
+    // Writes one row per intron, in position order, with 1-based inclusive
+    // coordinates
+    pub fn write(&self, out: &mut impl Write) -> Result<(), Error> {
+        writeln!(
+            out,
+            "#Chromosome\tStart\tEnd\tGenes\tSpliced\t\
+             StartBoundary\tEndBoundary\tIntronic\tRetentionRatio"
+        )?;
+        for (i, intron) in self.introns.iter().enumerate() {
+            let c = self.counts.get(&i).cloned().unwrap_or_default();
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                intron.seqname,
+                intron.start + 1,
+                intron.end,
+                intron.gene_ids.join(","),
+                c.spliced,
+                c.start_boundary,
+                c.end_boundary,
+                c.intronic,
+                c.retention_ratio()
+                    .map_or("NA".to_string(), |r| format!("{:.4}", r))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript(id: &str, gene_id: &str, exons: Vec<(i64, i64)>) -> Transcript {
+        Transcript {
+            id: id.to_string(),
+            gene_id: gene_id.to_string(),
+            seqname: "chr1".to_string(),
+            exons,
+        }
+    }
+
+    #[test]
+    fn test_intron_counts() {
+        let index = IntronIndex::new(&[
+            transcript("t1", "g1", vec![(100, 200), (300, 400), (500, 600)]),
+            transcript("t2", "g1", vec![(100, 200), (300, 400)]),
+        ]);
+        // Shared introns are listed once
+        let introns = index.introns();
+        assert_eq!(introns.len(), 2);
+        assert_eq!((introns[0].start, introns[0].end), (200, 300));
+        assert_eq!(introns[0].gene_ids, vec!["g1"]);
+
+        let mut counts = HashMap::new();
+        // Spliced across the first intron
+        index.count("chr1", &[(150, 200), (300, 350)], &mut counts);
+        // Crossing the start and end of the first intron without a gap
+        index.count("chr1", &[(180, 220)], &mut counts);
+        index.count("chr1", &[(280, 320)], &mut counts);
+        // Within the first intron
+        index.count("chr1", &[(230, 270)], &mut counts);
+        // Exonic only
+        index.count("chr1", &[(120, 180)], &mut counts);
+
+        let first = &counts[&0];
+        assert_eq!(
+            (
+                first.spliced,
+                first.start_boundary,
+                first.end_boundary,
+                first.intronic
+            ),
+            (1, 1, 1, 1)
+        );
+        assert_eq!(first.retention_ratio(), Some(0.5));
+        assert!(!counts.contains_key(&1));
+        assert_eq!(IntronCounts::default().retention_ratio(), None);
+    }
+}
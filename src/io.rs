@@ -1,29 +1,150 @@
-use crate::regions::{sort_regions_in_place, Region};
+use crate::regions::{merge_intervals, sort_regions_in_place, Gene, Region};
+use crate::transcripts::Transcript;
 use anyhow::Error;
+use bzip2::read::MultiBzDecoder;
 use csv::Reader;
 use flate2::read::MultiGzDecoder;
+use rust_htslib::tbx::{self, Read as _};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use xz2::read::XzDecoder;
 
+// Compression of a text file, detected from its first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Plain,
+    Gzip,
+    // Blocked gzip, as written by bgzip, which can be tabix-indexed
+    Bgzf,
+    Bzip2,
+    Xz,
+}
+
+pub fn detect_compression(magic: &[u8]) -> Compression {
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        // BGZF blocks are gzip members with a "BC" extra subfield
+        if magic.len() >= 14 && magic[3] & 4 != 0 && &magic[12..14] == b"BC" {
+            Compression::Bgzf
+        } else {
+            Compression::Gzip
+        }
+    } else if magic.starts_with(b"BZh") {
+        Compression::Bzip2
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+        Compression::Xz
+    } else {
+        Compression::Plain
+    }
+}
+
+// Wraps a reader in the decoder for the compression of its first bytes
+pub fn decompress(reader: impl Read + 'static) -> Result<Box<dyn BufRead>, Error> {
+    let mut reader = BufReader::new(reader);
+    Ok(match detect_compression(reader.fill_buf()?) {
+        Compression::Plain => Box::new(reader),
+        Compression::Gzip | Compression::Bgzf => {
+            Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+        }
+        Compression::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(reader))),
+        Compression::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+    })
+}
+
+// Standard input can only be read once, so an annotation read from it is
+// kept for the later passes over it
+static STDIN: OnceLock<Vec<u8>> = OnceLock::new();
+
+fn stdin_bytes() -> Result<&'static [u8], Error> {
+    if let Some(data) = STDIN.get() {
+        return Ok(data);
+    }
+    let mut data = vec![];
+    std::io::stdin().lock().read_to_end(&mut data)?;
+    Ok(STDIN.get_or_init(|| data))
+}
+
+// A GTF file, plain or compressed, or standard input if the path is "-"
 pub struct GtfFile {
     pub path: PathBuf,
+    regions: Vec<Region>,
 }
 
 impl GtfFile {
     pub fn new(file_path: impl Into<PathBuf>) -> Self {
         GtfFile {
             path: file_path.into(),
+            regions: vec![],
+        }
+    }
+
+    // Only loads the records overlapping these regions if the file is
+    // BGZF-compressed and tabix-indexed. Otherwise the whole file is read.
+    pub fn restrict_to(mut self, regions: Vec<Region>) -> Self {
+        self.regions = regions;
+        self
+    }
+
+    pub fn is_stdin(&self) -> bool {
+        self.path == Path::new("-")
+    }
+
+    // The regions loading is restricted to, if it is
+    pub fn tabix_regions(&self) -> Result<Option<&[Region]>, Error> {
+        if self.regions.is_empty() || self.is_stdin() {
+            return Ok(None);
+        }
+        let mut index = self.path.as_os_str().to_owned();
+        index.push(".tbi");
+        let mut csi = self.path.as_os_str().to_owned();
+        csi.push(".csi");
+        if !Path::new(&index).exists() && !Path::new(&csi).exists() {
+            return Ok(None);
+        }
+        let mut magic = vec![];
+        File::open(&self.path)?.take(18).read_to_end(&mut magic)?;
+        Ok((detect_compression(&magic) == Compression::Bgzf).then_some(self.regions.as_slice()))
+    }
+
+    // The bytes of the file as stored, before decompression
+    pub fn open_raw(&self) -> Result<Box<dyn Read>, Error> {
+        if self.is_stdin() {
+            Ok(Box::new(stdin_bytes()?))
+        } else {
+            Ok(Box::new(File::open(&self.path)?))
+        }
+    }
+
+    // The lines of a tabix-indexed file overlapping the regions, and all the
+    // lines of the genes they belong to, each line once. Genes crossing the
+    // edge of a region are loaded whole so that their exons and length are
+    // the same as without the restriction.
+    fn tabix_lines(&self, regions: &[Region]) -> Result<Vec<u8>, Error> {
+        let mut reader = tbx::Reader::from_path(&self.path)?;
+        let mut seen = HashSet::new();
+        let mut lines = vec![];
+        for region in regions {
+            fetch_lines(&mut reader, region, |_| true, &mut seen, &mut lines)?;
+        }
+        let spans = gene_spans(&lines);
+        for span in spans.values() {
+            let of_gene = |line: &[u8]| line_gene_id(line).is_some_and(|id| spans.contains_key(id));
+            fetch_lines(&mut reader, span, of_gene, &mut seen, &mut lines)?;
         }
+        Ok(lines)
     }
 
-    pub fn reader(&self) -> Result<Reader<BufReader<MultiGzDecoder<File>>>, Error> {
-        let file = File::open(&self.path)?;
-        let decoder = MultiGzDecoder::new(file);
-        let reader = BufReader::new(decoder);
+    pub fn reader(&self) -> Result<Reader<Box<dyn BufRead>>, Error> {
+        let reader: Box<dyn BufRead> = match self.tabix_regions()? {
+            Some(regions) => Box::new(std::io::Cursor::new(self.tabix_lines(regions)?)),
+            None => decompress(self.open_raw()?)?,
+        };
         let csv_reader = csv::ReaderBuilder::new()
             .delimiter(b'\t')
             .has_headers(false)
+            .quoting(false)
             .from_reader(reader);
         Ok(csv_reader)
     }
@@ -32,19 +153,369 @@ impl GtfFile {
     // 0-based, half-open intervals, sorts them by chromosome and position,
     // and returns them as a vector of Region structs.
     pub fn exon_regions(&self) -> Result<Vec<Region>, Error> {
-        let mut regions = vec![];
+        Ok(self.exon_and_gene_regions()?.0)
+    }
+
+    // Selects regions marked as "exon" and "gene", transforms their
+    // coordinates into 0-based, half-open intervals, sorts them by chromosome
+    // and position, and returns the exons as Region structs and the genes as
+    // Gene structs. If the file has no "gene" records, each gene spans the
+    // "transcript" records with its gene_id. Exons are assigned to genes, and
+    // genes take their biotype, by gene_id.
+    pub fn exon_and_gene_regions(&self) -> Result<(Vec<Region>, Vec<Gene>), Error> {
+        let mut exons = vec![];
+        let mut genes: Vec<(String, Region)> = vec![];
+        let mut transcript_spans: HashMap<String, Region> = HashMap::new();
+        let mut gene_exons: HashMap<String, Vec<(i64, i64)>> = HashMap::new();
+        let mut biotypes: HashMap<String, String> = HashMap::new();
         let mut reader = self.reader()?;
         for result in reader.records() {
             let record = result?;
-            if &record[2] == "exon" {
-                regions.push(Region {
-                    seqname: record[0].to_string(),
-                    start: (record[3].parse::<i64>()?) - 1i64,
-                    end: record[4].parse()?,
+            let feature = &record[2];
+            if !matches!(feature, "exon" | "gene" | "transcript") {
+                continue;
+            }
+            let region = Region {
+                seqname: record[0].to_string(),
+                start: (record[3].parse::<i64>()?) - 1i64,
+                end: record[4].parse()?,
+            };
+            let gene_id = attribute(&record[8], "gene_id")
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| {
+                    format!("{}:{}-{}", region.seqname, region.start + 1, region.end)
                 });
+            // Ensembl uses gene_biotype and GENCODE gene_type
+            let biotype = attribute(&record[8], "gene_biotype")
+                .or_else(|| attribute(&record[8], "gene_type"));
+            if let Some(biotype) = biotype {
+                if !biotypes.contains_key(&gene_id) {
+                    biotypes.insert(gene_id.clone(), biotype.to_string());
+                }
+            }
+            match feature {
+                "exon" => {
+                    gene_exons
+                        .entry(gene_id)
+                        .or_default()
+                        .push((region.start, region.end));
+                    exons.push(region);
+                }
+                "gene" => genes.push((gene_id, region)),
+                _ => {
+                    let span = transcript_spans
+                        .entry(gene_id)
+                        .or_insert_with(|| region.clone());
+                    span.start = span.start.min(region.start);
+                    span.end = span.end.max(region.end);
+                }
+            }
+        }
+        if genes.is_empty() {
+            genes = transcript_spans.into_iter().collect();
+        }
+        sort_regions_in_place(&mut exons);
+        genes.sort_by(|a, b| {
+            a.1.seqname
+                .cmp(&b.1.seqname)
+                .then_with(|| a.1.start.cmp(&b.1.start))
+                .then_with(|| a.1.end.cmp(&b.1.end))
+                .then_with(|| a.0.cmp(&b.0))
+        });
+        let genes = genes
+            .into_iter()
+            .enumerate()
+            .map(|(index, (id, region))| Gene {
+                index,
+                biotype: biotypes.remove(&id),
+                exons: merge_intervals(gene_exons.remove(&id).unwrap_or_default()),
+                id,
+                region,
+            })
+            .collect();
+        Ok((exons, genes))
+    }
+
+    // Collects the exons of each transcript_id into transcripts, sorted by
+    // chromosome, start and id. Exons without a transcript_id are skipped.
+    pub fn transcripts(&self) -> Result<Vec<Transcript>, Error> {
+        let mut transcripts: HashMap<String, Transcript> = HashMap::new();
+        let mut reader = self.reader()?;
+        for result in reader.records() {
+            let record = result?;
+            if &record[2] != "exon" {
+                continue;
+            }
+            let Some(transcript_id) = attribute(&record[8], "transcript_id") else {
+                continue;
+            };
+            let start = record[3].parse::<i64>()? - 1;
+            let end = record[4].parse::<i64>()?;
+            transcripts
+                .entry(transcript_id.to_string())
+                .or_insert_with(|| Transcript {
+                    id: transcript_id.to_string(),
+                    gene_id: attribute(&record[8], "gene_id")
+                        .unwrap_or_default()
+                        .to_string(),
+                    seqname: record[0].to_string(),
+                    exons: vec![],
+                })
+                .exons
+                .push((start, end));
+        }
+        let mut transcripts: Vec<Transcript> = transcripts
+            .into_values()
+            .map(|mut transcript| {
+                transcript.exons = merge_intervals(transcript.exons);
+                transcript
+            })
+            .collect();
+        transcripts.sort_by(|a, b| {
+            a.seqname
+                .cmp(&b.seqname)
+                .then_with(|| a.exons.first().cmp(&b.exons.first()))
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        Ok(transcripts)
+    }
+}
+
+// Appends the lines of a tabix-indexed file overlapping the region that are
+// kept and not yet seen
+fn fetch_lines(
+    reader: &mut tbx::Reader,
+    region: &Region,
+    keep: impl Fn(&[u8]) -> bool,
+    seen: &mut HashSet<Vec<u8>>,
+    lines: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let Ok(tid) = reader.tid(&region.seqname) else {
+        return Ok(());
+    };
+    reader.fetch(tid, region.start as u64, region.end as u64)?;
+    let mut line = vec![];
+    while reader.read(&mut line)? {
+        if keep(&line) && seen.insert(line.clone()) {
+            lines.extend_from_slice(&line);
+            lines.push(b'\n');
+        }
+    }
+    Ok(())
+}
+
+// The gene_id of a GTF line, if it has one
+fn line_gene_id(line: &[u8]) -> Option<&str> {
+    let attributes = line.split(|&byte| byte == b'\t').nth(8)?;
+    attribute(std::str::from_utf8(attributes).ok()?, "gene_id")
+}
+
+// The span of each gene with records among the GTF lines, from all its
+// records: the gene record covers all of its exons, or failing that its
+// transcript records do
+fn gene_spans(lines: &[u8]) -> HashMap<String, Region> {
+    let mut spans: HashMap<String, Region> = HashMap::new();
+    for line in lines.split(|&byte| byte == b'\n') {
+        let Some(gene_id) = line_gene_id(line) else {
+            continue;
+        };
+        let fields: Vec<_> = line.split(|&byte| byte == b'\t').collect();
+        let position = |i: usize| std::str::from_utf8(fields[i]).ok()?.parse::<i64>().ok();
+        let (Some(start), Some(end)) = (position(3), position(4)) else {
+            continue;
+        };
+        let span = spans.entry(gene_id.to_string()).or_insert_with(|| Region {
+            seqname: String::from_utf8_lossy(fields[0]).to_string(),
+            start: start - 1,
+            end,
+        });
+        span.start = span.start.min(start - 1);
+        span.end = span.end.max(end);
+    }
+    spans
+}
+
+// Returns the value of a GTF attribute, e.g. `gene_id "ENSG00000223972";`,
+// without its quotes.
+pub fn attribute<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    attributes.split(';').find_map(|field| {
+        let (name, value) = field.trim().split_once(' ')?;
+        (name == key).then(|| value.trim().trim_matches('"'))
+    })
+}
+
+// Opens a text file, decompressing it if it is compressed
+fn open_text(path: &Path) -> Result<Box<dyn BufRead>, Error> {
+    decompress(File::open(path)?)
+}
+
+// Reads a cell barcode whitelist with one barcode per line, optionally
+// compressed
+pub fn read_barcode_whitelist(path: &Path) -> Result<HashSet<Vec<u8>>, Error> {
+    let reader = open_text(path)?;
+    let mut whitelist = HashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let barcode = line.trim();
+        if !barcode.is_empty() {
+            whitelist.insert(barcode.as_bytes().to_vec());
+        }
+    }
+    Ok(whitelist)
+}
+
+// Reads a table of spike-in concentrations: a spike-in name and its
+// concentration, in any unit, separated by tabs. Lines starting with '#' and
+// a header line whose concentration is not a number are skipped.
+pub fn read_spike_in_concentrations(path: &Path) -> Result<HashMap<String, f64>, Error> {
+    let mut concentrations = HashMap::new();
+    for (i, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 2 {
+            return Err(Error::msg(format!(
+                "Expected a name and a concentration in {} line {}",
+                path.display(),
+                i + 1
+            )));
+        }
+        match fields[1].trim().parse::<f64>() {
+            Ok(concentration) => {
+                concentrations.insert(fields[0].trim().to_string(), concentration);
             }
+            Err(_) if concentrations.is_empty() => continue,
+            Err(_) => {
+                return Err(Error::msg(format!(
+                    "Invalid concentration '{}' in {} line {}",
+                    fields[1],
+                    path.display(),
+                    i + 1
+                )))
+            }
+        }
+    }
+    Ok(concentrations)
+}
+
+// Parses a BED line into a region and its name, if it has one. BED
+// coordinates are already 0-based and half-open. Header, track and browser
+// lines return None.
+fn parse_bed_line(line: &str) -> Result<Option<(Region, Option<String>)>, Error> {
+    if line.trim().is_empty()
+        || line.starts_with('#')
+        || line.starts_with("track")
+        || line.starts_with("browser")
+    {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 3 {
+        return Err(Error::msg(format!("Invalid BED line: {}", line)));
+    }
+    let region = Region {
+        seqname: fields[0].to_string(),
+        start: fields[1].trim().parse()?,
+        end: fields[2].trim().parse()?,
+    };
+    let name = fields
+        .get(3)
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string());
+    Ok(Some((region, name)))
+}
+
+// Reads the regions of a BED file and their names, optionally compressed
+pub fn read_bed_named_regions(path: &Path) -> Result<Vec<(Region, Option<String>)>, Error> {
+    let mut regions = vec![];
+    for line in open_text(path)?.lines() {
+        if let Some(region) = parse_bed_line(&line?)? {
+            regions.push(region);
+        }
+    }
+    Ok(regions)
+}
+
+pub fn read_bed_regions(path: &Path) -> Result<Vec<Region>, Error> {
+    Ok(read_bed_named_regions(path)?
+        .into_iter()
+        .map(|(region, _)| region)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribute() {
+        let attributes = r#"gene_id "ENSG00000223972.5"; gene_type "transcribed_unprocessed_pseudogene"; gene_name "DDX11L1"; level 2;"#;
+        assert_eq!(attribute(attributes, "gene_id"), Some("ENSG00000223972.5"));
+        assert_eq!(attribute(attributes, "gene_name"), Some("DDX11L1"));
+        assert_eq!(attribute(attributes, "level"), Some("2"));
+        assert_eq!(attribute(attributes, "transcript_id"), None);
+    }
+
+    #[test]
+    fn test_decompress() {
+        use std::io::Write;
+        let text = b"chr1\tHAVANA\tgene\t1\t100\n";
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(text).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut bzip2 = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+        bzip2.write_all(text).unwrap();
+        let bzip2 = bzip2.finish().unwrap();
+        let mut xz = xz2::write::XzEncoder::new(vec![], 6);
+        xz.write_all(text).unwrap();
+        let xz = xz.finish().unwrap();
+        assert_eq!(detect_compression(&gzip), Compression::Gzip);
+        assert_eq!(detect_compression(&bzip2), Compression::Bzip2);
+        assert_eq!(detect_compression(&xz), Compression::Xz);
+        assert_eq!(detect_compression(text), Compression::Plain);
+        // Header of an empty BGZF block
+        assert_eq!(
+            detect_compression(&[31, 139, 8, 4, 0, 0, 0, 0, 0, 255, 6, 0, 66, 67, 2, 0]),
+            Compression::Bgzf
+        );
+        for data in [text.to_vec(), gzip, bzip2, xz] {
+            let mut decompressed = vec![];
+            decompress(std::io::Cursor::new(data))
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, text);
         }
-        sort_regions_in_place(&mut regions);
-        Ok(regions)
+    }
+
+    #[test]
+    fn test_gene_spans() {
+        let lines = b"chr1\tHAVANA\tgene\t101\t900\t.\t+\t.\tgene_id \"g1\";\n\
+                      chr1\tHAVANA\texon\t801\t900\t.\t+\t.\tgene_id \"g1\";\n\
+                      chr1\tHAVANA\texon\t1001\t1100\t.\t+\t.\tgene_id \"g2\";\n\
+                      chr1\tHAVANA\texon\t1201\t1300\t.\t+\t.\n";
+        let spans = gene_spans(lines);
+        assert_eq!(spans.len(), 2);
+        assert_eq!((spans["g1"].start, spans["g1"].end), (100, 900));
+        assert_eq!(spans["g2"].seqname, "chr1");
+        assert_eq!((spans["g2"].start, spans["g2"].end), (1000, 1100));
+    }
+
+    #[test]
+    fn test_parse_bed_line() {
+        let (region, name) = parse_bed_line("chr1\t100\t200\ttarget1\t0\t+")
+            .unwrap()
+            .unwrap();
+        assert_eq!(region.seqname, "chr1");
+        assert_eq!((region.start, region.end), (100, 200));
+        assert_eq!(name.as_deref(), Some("target1"));
+        let (_, name) = parse_bed_line("chr1\t100\t200").unwrap().unwrap();
+        assert_eq!(name, None);
+        assert!(parse_bed_line("track name=targets").unwrap().is_none());
+        assert!(parse_bed_line("# comment").unwrap().is_none());
+        assert!(parse_bed_line("chr1\t100").is_err());
     }
 }
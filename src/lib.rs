@@ -0,0 +1,40 @@
+// Counts the reads of an RNA-seq BAM file that fall in exons, introns and
+// intergenic regions of a GTF annotation. `Counter` runs a count and returns
+// a `CountReport`; the modules below are also usable on their own.
+pub mod annotate;
+pub mod annotation_cache;
+pub mod barcodes;
+pub mod chimeric;
+pub mod chromosomes;
+mod cigar;
+pub mod classify;
+pub mod counter;
+pub mod coverage;
+mod dedup;
+pub mod distributions;
+mod duplicates;
+pub mod expression;
+pub mod extract;
+pub mod introns;
+pub mod io;
+pub mod library;
+mod long_read;
+pub mod normalise;
+pub mod read_groups;
+pub mod reference;
+pub mod regions;
+pub mod report;
+mod sampling;
+pub mod saturation;
+pub mod spike_ins;
+pub mod transcripts;
+
+pub use classify::{ReadClassifier, RegionCategory};
+pub use counter::{
+    CategoryCounts, CountResult, Counter, Preset, ReadCheckOutcome, ReadFilter, UmiMethod,
+};
+pub(crate) use counter::{ReadInfo, FLAG_PROPER_PAIR};
+pub use expression::Expression;
+pub use io::GtfFile;
+pub use regions::{index_regions, ChromosomeRegions, Region};
+pub use report::CountReport;
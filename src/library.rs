@@ -0,0 +1,71 @@
+use crate::reference::open_bam;
+use anyhow::Error;
+use rust_htslib::bam::{Read, Record};
+use std::path::Path;
+
+const FLAG_PAIRED: u16 = 1;
+const FLAGS_NOT_PRIMARY: u16 = 2304; // Secondary or supplementary
+const LAYOUT_SAMPLE_SIZE: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibraryLayout {
+    Paired,
+    Single,
+}
+
+// Classifies the library from the paired flag of the first primary records
+// in the file, decoding a CRAM file with `reference` if given. Returns None
+// if the file contains no primary records.
+pub fn detect_library_layout(
+    bamfile: &Path,
+    reference: Option<&Path>,
+) -> Result<Option<LibraryLayout>, Error> {
+    let mut bam = open_bam(bamfile, reference)?;
+    let mut read = Record::new();
+    let mut sampled = 0;
+    let mut paired = 0;
+    while sampled < LAYOUT_SAMPLE_SIZE {
+        match bam.read(&mut read) {
+            Some(Ok(_)) => {
+                if read.flags() & FLAGS_NOT_PRIMARY != 0 {
+                    continue;
+                }
+                sampled += 1;
+                if read.flags() & FLAG_PAIRED != 0 {
+                    paired += 1;
+                }
+            }
+            Some(Err(e)) => return Err(e.into()),
+            None => break,
+        }
+    }
+    Ok(layout_from_sample(sampled, paired))
+}
+
+fn layout_from_sample(sampled: usize, paired: usize) -> Option<LibraryLayout> {
+    if sampled == 0 {
+        None
+    } else if paired * 2 >= sampled {
+        Some(LibraryLayout::Paired)
+    } else {
+        Some(LibraryLayout::Single)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_from_empty_sample() {
+        assert_eq!(layout_from_sample(0, 0), None);
+    }
+
+    #[test]
+    fn test_layout_from_sample() {
+        assert_eq!(layout_from_sample(100, 100), Some(LibraryLayout::Paired));
+        assert_eq!(layout_from_sample(100, 50), Some(LibraryLayout::Paired));
+        assert_eq!(layout_from_sample(100, 2), Some(LibraryLayout::Single));
+        assert_eq!(layout_from_sample(100, 0), Some(LibraryLayout::Single));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::classify::RegionCategory;
+use crate::ReadInfo;
+
+// Aligned bases of a read, and how many of them fall in exons and genes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlignedBases {
+    pub aligned: i64,
+    pub exon: i64,
+    pub gene: i64,
+}
+
+impl AlignedBases {
+    pub fn add(&mut self, other: &AlignedBases) {
+        self.aligned += other.aligned;
+        self.exon += other.exon;
+        self.gene += other.gene;
+    }
+
+    // Fraction of aligned bases that fall in exons; 0 for reads with no
+    // aligned bases.
+    pub fn exon_fraction(&self) -> f64 {
+        if self.aligned == 0 {
+            0.0
+        } else {
+            self.exon as f64 / self.aligned as f64
+        }
+    }
+
+    // A read is exonic if any of its aligned bases are in an exon and, when a
+    // minimum fraction is given, at least that fraction of them are.
+    // Otherwise it is intronic if any aligned bases are within a gene.
+    pub fn category(&self, min_exon_fraction: Option<f64>) -> RegionCategory {
+        if self.exon > 0 && min_exon_fraction.is_none_or(|min| self.exon_fraction() >= min) {
+            RegionCategory::Exon
+        } else if self.gene > 0 {
+            RegionCategory::Intron
+        } else {
+            RegionCategory::Intergenic
+        }
+    }
+}
+
+// Bases accumulated over all segments (primary and supplementary alignments)
+// of a long read that is split across several alignment records, possibly
+// on different chromosomes.
+#[derive(Debug, Clone, Default)]
+pub struct SplitRead {
+    pub bases: AlignedBases,
+    // Set once the primary alignment is seen
+    pub primary: Option<ReadInfo>,
+}
+
+impl SplitRead {
+    pub fn merge(&mut self, other: SplitRead) {
+        self.bases.add(&other.bases);
+        if other.primary.is_some() {
+            self.primary = other.primary;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReadCheckOutcome;
+
+    #[test]
+    fn test_split_read_takes_outcome_from_primary() {
+        let mut read = SplitRead {
+            bases: AlignedBases {
+                aligned: 100,
+                exon: 50,
+                gene: 50,
+            },
+            primary: None,
+        };
+        let primary = SplitRead {
+            bases: AlignedBases {
+                aligned: 300,
+                exon: 250,
+                gene: 300,
+            },
+            primary: Some(ReadInfo {
+                outcome: ReadCheckOutcome::Accept,
+                cell: None,
+                molecule: None,
+                duplicate: false,
+                estimated_duplicate: false,
+                saturation: None,
+                classes: vec![],
+                read_group: None,
+                chromosome: None,
+                equivalence_class: None,
+            }),
+        };
+        read.merge(primary);
+        assert_eq!(read.bases.aligned, 400);
+        assert_eq!(read.bases.exon, 300);
+        assert!(matches!(
+            read.primary.map(|p| p.outcome),
+            Some(ReadCheckOutcome::Accept)
+        ));
+    }
+
+    #[test]
+    fn test_aligned_bases_category() {
+        let bases = |exon, gene| AlignedBases {
+            aligned: 100,
+            exon,
+            gene,
+        };
+        assert_eq!(bases(0, 0).category(None), RegionCategory::Intergenic);
+        assert_eq!(bases(0, 10).category(None), RegionCategory::Intron);
+        assert_eq!(bases(1, 10).category(None), RegionCategory::Exon);
+        assert_eq!(bases(49, 100).category(Some(0.5)), RegionCategory::Intron);
+        assert_eq!(bases(50, 100).category(Some(0.5)), RegionCategory::Exon);
+        assert_eq!(AlignedBases::default().exon_fraction(), 0.0);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::regions::Gene;
+use crate::CountResult;
+use anyhow::Error;
+use serde::Serialize;
+use std::io::Write;
+
+// Normalised expression of one gene
+#[derive(Debug, Clone, Serialize)]
+pub struct GeneExpression {
+    pub id: String,
+    // Total length of the union of the gene's exons
+    pub length: i64,
+    // Number of positions a fragment of the mean length can start at within
+    // the exons. None if the gene is shorter than the mean fragment.
+    pub effective_length: Option<f64>,
+    // Accepted reads overlapping the gene's exons
+    pub count: usize,
+    pub fpkm: f64,
+    pub tpm: f64,
+}
+
+// Computes FPKM and TPM from the accepted reads of each gene. Paired reads
+// are counted twice per fragment, so `reads_per_fragment` is 2 for paired
+// libraries. Without a mean fragment length the effective length is the
+// exon length. As in RSEM, genes shorter than the mean fragment have no
+// effective length and an expression of 0.
+pub fn gene_expression(
+    genes: &[Gene],
+    counts: &[CountResult],
+    mapped_reads: usize,
+    reads_per_fragment: f64,
+    fragment_length: Option<f64>,
+) -> Vec<GeneExpression> {
+    let mut expression: Vec<GeneExpression> = genes
+        .iter()
+        .zip(counts)
+        .map(|(gene, counts)| {
+            let length: i64 = gene.exons.iter().map(|(start, end)| end - start).sum();
+            let effective_length = match fragment_length {
+                Some(fragment_length) => Some(length as f64 - fragment_length + 1.0),
+                None => Some(length as f64),
+            }
+            .filter(|&l| l >= 1.0);
+            GeneExpression {
+                id: gene.id.clone(),
+                length,
+                effective_length,
+                count: counts.accepted,
+                fpkm: 0.0,
+                tpm: 0.0,
+            }
+        })
+        .collect();
+    let fragments = |gene: &GeneExpression| gene.count as f64 / reads_per_fragment;
+    let rates: Vec<f64> = expression
+        .iter()
+        .map(|gene| gene.effective_length.map_or(0.0, |l| fragments(gene) / l))
+        .collect();
+    let total_rate: f64 = rates.iter().sum();
+    let mapped_fragments = mapped_reads as f64 / reads_per_fragment;
+    for (gene, rate) in expression.iter_mut().zip(rates) {
+        if mapped_fragments > 0.0 {
+            gene.fpkm = rate * 1e9 / mapped_fragments;
+        }
+        if total_rate > 0.0 {
+            gene.tpm = rate * 1e6 / total_rate;
+        }
+    }
+    expression
+}
+
+// Writes one row per gene, in annotation order
+pub fn write_gene_table(out: &mut impl Write, genes: &[GeneExpression]) -> Result<(), Error> {
+    writeln!(out, "#Gene\tLength\tEffectiveLength\tCount\tFPKM\tTPM")?;
+    for gene in genes {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{:.4}\t{:.4}",
+            gene.id,
+            gene.length,
+            gene.effective_length
+                .map_or("NA".to_string(), |l| format!("{:.1}", l)),
+            gene.count,
+            gene.fpkm,
+            gene.tpm
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Region;
+
+    fn gene(index: usize, exons: Vec<(i64, i64)>) -> Gene {
+        Gene {
+            id: format!("gene{}", index),
+            index,
+            biotype: None,
+            region: Region {
+                seqname: "chr1".to_string(),
+                start: exons[0].0,
+                end: exons.last().unwrap().1,
+            },
+            exons,
+        }
+    }
+
+    fn accepted(accepted: usize) -> CountResult {
+        CountResult {
+            accepted,
+            rejected: 0,
+        }
+    }
+
+    #[test]
+    fn test_gene_expression() {
+        let genes = vec![
+            gene(0, vec![(0, 500), (1000, 1500)]),
+            gene(1, vec![(2000, 4000)]),
+            gene(2, vec![(5000, 5100)]),
+        ];
+        let counts = vec![accepted(100), accepted(100), accepted(10)];
+
+        let expression = gene_expression(&genes, &counts, 1_000_000, 1.0, None);
+        assert_eq!(expression[0].length, 1000);
+        assert_eq!(expression[0].effective_length, Some(1000.0));
+        // 100 reads per kilobase per million mapped reads
+        assert!((expression[0].fpkm - 100.0).abs() < 1e-9);
+        assert!((expression[1].fpkm - 50.0).abs() < 1e-9);
+        let total_tpm: f64 = expression.iter().map(|gene| gene.tpm).sum();
+        assert!((total_tpm - 1e6).abs() < 1e-6);
+        assert!((expression[0].tpm - 2.0 * expression[1].tpm).abs() < 1e-6);
+
+        // Paired reads count once per fragment, and genes shorter than the
+        // mean fragment get no expression
+        let expression = gene_expression(&genes, &counts, 2_000_000, 2.0, Some(201.0));
+        assert_eq!(expression[0].effective_length, Some(800.0));
+        assert!((expression[0].fpkm - 62.5).abs() < 1e-9);
+        assert_eq!(expression[2].effective_length, None);
+        assert_eq!((expression[2].fpkm, expression[2].tpm), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::reference::open_bam;
+use crate::{CategoryCounts, CountResult};
+use anyhow::Error;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::{Read, Record};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Label for reads without an RG tag, or with one not declared in the header
+pub const UNKNOWN_READ_GROUP: &str = "unknown";
+
+// The read groups declared in the BAM header. Reads are assigned to the
+// index of their group, or to the index one past the last group if their
+// group is unknown.
+#[derive(Debug, Clone, Default)]
+pub struct ReadGroups {
+    ids: Vec<String>,
+    index: HashMap<Vec<u8>, usize>,
+}
+
+impl ReadGroups {
+    pub fn new(ids: Vec<String>) -> Self {
+        let index = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (id.as_bytes().to_vec(), i))
+            .collect();
+        ReadGroups { ids, index }
+    }
+
+    pub fn from_bam(bamfile: &Path, reference: Option<&Path>) -> Result<Self, Error> {
+        let bam = open_bam(bamfile, reference)?;
+        let header = String::from_utf8_lossy(bam.header().as_bytes()).to_string();
+        Ok(ReadGroups::new(header_read_group_ids(&header)))
+    }
+
+    // Number of groups, including the unknown group
+    pub fn group_count(&self) -> usize {
+        self.ids.len() + 1
+    }
+
+    pub fn id(&self, index: usize) -> &str {
+        self.ids
+            .get(index)
+            .map_or(UNKNOWN_READ_GROUP, |id| id.as_str())
+    }
+
+    pub fn index_of(&self, read: &Record) -> usize {
+        match read.aux(b"RG") {
+            Ok(Aux::String(id)) => self
+                .index
+                .get(id.as_bytes())
+                .copied()
+                .unwrap_or(self.ids.len()),
+            _ => self.ids.len(),
+        }
+    }
+}
+
+// IDs of the @RG lines of a SAM header, in order
+fn header_read_group_ids(header: &str) -> Vec<String> {
+    header
+        .lines()
+        .filter(|line| line.starts_with("@RG\t"))
+        .filter_map(|line| {
+            line.split('\t')
+                .find_map(|field| field.strip_prefix("ID:"))
+                .map(|id| id.to_string())
+        })
+        .collect()
+}
+
+// Counts of one read group
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadGroupReport {
+    pub id: String,
+    #[serde(flatten)]
+    pub categories: CategoryCounts,
+    pub mapped: CountResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unmapped: Option<CountResult>,
+    pub total: CountResult,
+}
+
+// Builds the reports of the read groups with any reads, from the category
+// counts of each group and, unless they were not counted, their unmapped
+// reads. `labels` are the categories of the counts.
+pub fn read_group_reports(
+    groups: &ReadGroups,
+    categories: &[CategoryCounts],
+    unmapped: Option<&[CountResult]>,
+    labels: &[String],
+) -> Vec<ReadGroupReport> {
+    (0..groups.group_count())
+        .filter_map(|i| {
+            let categories = categories
+                .get(i)
+                .cloned()
+                .unwrap_or_else(|| CategoryCounts::new(labels));
+            let mapped = categories.mapped();
+            let unmapped = unmapped.map(|u| u.get(i).cloned().unwrap_or_default());
+            let mut total = mapped.clone();
+            if let Some(unmapped) = &unmapped {
+                total.merge(unmapped);
+            }
+            // Declared groups are always reported; the unknown group only
+            // if it has reads
+            if i + 1 == groups.group_count() && total.total() == 0 {
+                return None;
+            }
+            Some(ReadGroupReport {
+                id: groups.id(i).to_string(),
+                categories,
+                mapped,
+                unmapped,
+                total,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_read_group_ids() {
+        let header = "@HD\tVN:1.6\tSO:coordinate\n\
+                      @SQ\tSN:chr1\tLN:1000\n\
+                      @RG\tID:lane1\tSM:sample\tPL:ILLUMINA\n\
+                      @RG\tSM:sample\tID:lane2\n\
+                      @PG\tID:bwa\tPN:bwa\n";
+        assert_eq!(header_read_group_ids(header), vec!["lane1", "lane2"]);
+    }
+
+    #[test]
+    fn test_read_group_index() {
+        let groups = ReadGroups::new(vec!["lane1".to_string(), "lane2".to_string()]);
+        let mut read = Record::new();
+        assert_eq!(groups.index_of(&read), 2);
+        read.push_aux(b"RG", Aux::String("lane2")).unwrap();
+        assert_eq!(groups.index_of(&read), 1);
+        assert_eq!(groups.id(1), "lane2");
+        assert_eq!(groups.id(2), UNKNOWN_READ_GROUP);
+    }
+
+    #[test]
+    fn test_read_group_reports_skip_empty_unknown() {
+        let groups = ReadGroups::new(vec!["lane1".to_string()]);
+        let labels = vec!["Exon".to_string()];
+        let mut categories = vec![CategoryCounts::new(&labels); 2];
+        categories[0].add(&[0], &crate::ReadCheckOutcome::Accept);
+        let reports = read_group_reports(&groups, &categories, None, &labels);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].id, "lane1");
+        assert_eq!(reports[0].mapped.accepted, 1);
+        assert_eq!(reports[0].total.accepted, 1);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::io::decompress;
+use anyhow::Error;
+use rust_htslib::bam::{IndexedReader, Read, Reader};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufRead;
+use std::path::Path;
+
+// Length and MD5 of a reference sequence, as in the LN and M5 fields of an
+// @SQ header line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contig {
+    pub length: u64,
+    pub md5: Option<String>,
+}
+
+// Names, lengths and optional MD5s of the @SQ lines of a SAM header
+fn header_contigs(header: &str) -> Vec<(String, Contig)> {
+    header
+        .lines()
+        .filter(|line| line.starts_with("@SQ\t"))
+        .filter_map(|line| {
+            let field = |tag: &str| line.split('\t').find_map(|field| field.strip_prefix(tag));
+            Some((
+                field("SN:")?.to_string(),
+                Contig {
+                    length: field("LN:")?.parse().ok()?,
+                    md5: field("M5:").map(|md5| md5.to_ascii_lowercase()),
+                },
+            ))
+        })
+        .collect()
+}
+
+// Lengths and MD5s of the sequences of a FASTA file, by the first word of
+// their name line. As for M5, the MD5 is of the sequence in upper case
+// without whitespace or other characters outside '!' to '~'.
+pub fn fasta_contigs(reader: impl BufRead) -> Result<HashMap<String, Contig>, Error> {
+    let mut contigs = HashMap::new();
+    let mut current: Option<(String, u64, md5::Context)> = None;
+    let mut finish = |current: Option<(String, u64, md5::Context)>| {
+        if let Some((name, length, md5)) = current {
+            let md5 = format!("{:x}", md5.compute());
+            contigs.insert(
+                name,
+                Contig {
+                    length,
+                    md5: Some(md5),
+                },
+            );
+        }
+    };
+    for line in reader.split(b'\n') {
+        let line = line?;
+        if let Some(name) = line.strip_prefix(b">") {
+            let name = String::from_utf8_lossy(name);
+            let name = name.split_whitespace().next().unwrap_or_default();
+            finish(current.replace((name.to_string(), 0, md5::Context::new())));
+            continue;
+        }
+        if let Some((_, length, md5)) = current.as_mut() {
+            let bases: Vec<u8> = line
+                .iter()
+                .filter(|base| (b'!'..=b'~').contains(*base))
+                .map(|base| base.to_ascii_uppercase())
+                .collect();
+            *length += bases.len() as u64;
+            md5.consume(&bases);
+        }
+    }
+    finish(current);
+    Ok(contigs)
+}
+
+// Problems with a reference for the contigs of a header: contigs missing
+// from the reference, or with another length or MD5
+fn reference_mismatches(
+    header: &[(String, Contig)],
+    reference: &HashMap<String, Contig>,
+) -> Vec<String> {
+    let mut mismatches = vec![];
+    for (name, contig) in header {
+        let Some(sequence) = reference.get(name) else {
+            mismatches.push(format!("{} is not in the reference", name));
+            continue;
+        };
+        if sequence.length != contig.length {
+            mismatches.push(format!(
+                "{} has length {} in the header but {} in the reference",
+                name, contig.length, sequence.length
+            ));
+        } else if let (Some(expected), Some(actual)) = (&contig.md5, &sequence.md5) {
+            if expected != actual {
+                mismatches.push(format!(
+                    "{} has M5 {} in the header but {} in the reference",
+                    name, expected, actual
+                ));
+            }
+        }
+    }
+    mismatches
+}
+
+// Opens a BAM or CRAM file, with the reference for decoding CRAM if one is
+// given. Without it, CRAM decoding falls back on the REF_PATH environment
+// variable, so every reader of the input is opened here.
+pub fn open_bam(bamfile: &Path, reference: Option<&Path>) -> Result<Reader, Error> {
+    let mut bam = Reader::from_path(bamfile)?;
+    if let Some(reference) = reference {
+        bam.set_reference(reference)?;
+    }
+    Ok(bam)
+}
+
+// As open_bam, for fetching regions through the index
+pub fn open_indexed_bam(bamfile: &Path, reference: Option<&Path>) -> Result<IndexedReader, Error> {
+    let mut bam = IndexedReader::from_path(bamfile)?;
+    if let Some(reference) = reference {
+        bam.set_reference(reference)?;
+    }
+    Ok(bam)
+}
+
+// Checks that a reference FASTA file has every contig of the BAM or CRAM
+// header, with the same length and, where the header has an M5 field, the
+// same MD5
+pub fn check_reference(bamfile: &Path, reference: &Path) -> Result<(), Error> {
+    let bam = open_bam(bamfile, Some(reference))?;
+    let header = header_contigs(&String::from_utf8_lossy(bam.header().as_bytes()));
+    let contigs = fasta_contigs(decompress(File::open(reference)?)?)?;
+    let mismatches = reference_mismatches(&header, &contigs);
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    // The first few are enough to tell a wrong reference from a wrong build
+    const SHOWN: usize = 5;
+    let mut message = format!(
+        "Reference {} does not match the header of {}: {} of {} contigs differ\n  {}",
+        reference.display(),
+        bamfile.display(),
+        mismatches.len(),
+        header.len(),
+        mismatches[..mismatches.len().min(SHOWN)].join("\n  ")
+    );
+    if mismatches.len() > SHOWN {
+        message.push_str(&format!("\n  and {} more", mismatches.len() - SHOWN));
+    }
+    Err(Error::msg(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_mismatches() {
+        let fasta = b">chr1 first\nacgt\nACGT\n>chr2\nAC GT\r\n>chrM\nA\n";
+        let reference = fasta_contigs(&fasta[..]).unwrap();
+        assert_eq!(reference["chr1"].length, 8);
+        // The MD5 of ACGTACGT
+        assert_eq!(
+            reference["chr1"].md5.as_deref(),
+            Some("cc0af3a4fedb18378b4b57b98068e69f")
+        );
+        assert_eq!(reference["chr2"].length, 4);
+
+        let header = header_contigs(
+            "@HD\tVN:1.6\n\
+             @SQ\tSN:chr1\tLN:8\tM5:CC0AF3A4FEDB18378B4B57B98068E69F\n\
+             @SQ\tSN:chr2\tLN:4\tM5:00000000000000000000000000000000\n\
+             @SQ\tSN:chrM\tLN:2\n\
+             @SQ\tSN:chrX\tLN:10\n",
+        );
+        assert_eq!(header.len(), 4);
+        assert_eq!(
+            reference_mismatches(&header, &reference),
+            vec![
+                "chr2 has M5 00000000000000000000000000000000 in the header but \
+                 f1f8f4bf413b16ad135722aa4591043e in the reference",
+                "chrM has length 2 in the header but 1 in the reference",
+                "chrX is not in the reference",
+            ]
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use anyhow::Error;
 use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
@@ -18,10 +19,13 @@ pub fn sort_regions_in_place(regions: &mut [Region]) {
 
 pub fn compress_regions(regions: &[Region]) -> Vec<Region> {
     let mut compressed = vec![];
+    if regions.is_empty() {
+        return compressed;
+    }
     let mut current = regions[0].clone();
     for region in regions.iter().skip(1) {
         if region.seqname == current.seqname && region.start <= current.end {
-            current.end = region.end;
+            current.end = current.end.max(region.end);
         } else {
             compressed.push(current.clone());
             current = region.clone();
@@ -31,6 +35,19 @@ pub fn compress_regions(regions: &[Region]) -> Vec<Region> {
     compressed
 }
 
+// Sorts intervals and merges those that overlap
+pub fn merge_intervals(mut intervals: Vec<(i64, i64)>) -> Vec<(i64, i64)> {
+    intervals.sort();
+    let mut merged: Vec<(i64, i64)> = vec![];
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
 // Converts a vector of regions into a hashmap, where the key is the
 // chromosome name and the value is a sorted vector of regions on that chromosome.
 pub fn convert_regions_vec_to_hashmap(regions: Vec<Region>) -> HashMap<String, Vec<Region>> {
@@ -45,6 +62,177 @@ pub fn convert_regions_vec_to_hashmap(regions: Vec<Region>) -> HashMap<String, V
     regions_map
 }
 
+// An annotated gene, the region it spans and its merged exons. `index` is
+// the gene's position in the sorted annotation.
+#[derive(Debug, Clone)]
+pub struct Gene {
+    pub id: String,
+    pub index: usize,
+    pub biotype: Option<String>,
+    pub region: Region,
+    pub exons: Vec<(i64, i64)>,
+}
+
+// Compressed exon and gene regions on one chromosome, and the individual
+// genes sorted by start position
+#[derive(Debug, Clone, Default)]
+pub struct ChromosomeRegions {
+    pub exons: Vec<Region>,
+    pub genes: Vec<Region>,
+    pub gene_spans: Vec<Gene>,
+}
+
+// Compresses exon and gene regions and groups them by chromosome. The genes
+// must be sorted by chromosome and position.
+pub fn index_regions(exons: &[Region], genes: &[Gene]) -> HashMap<String, ChromosomeRegions> {
+    let mut index: HashMap<String, ChromosomeRegions> = HashMap::new();
+    for (chrom, regions) in convert_regions_vec_to_hashmap(compress_regions(exons)) {
+        index.entry(chrom).or_default().exons = regions;
+    }
+    let gene_regions: Vec<Region> = genes.iter().map(|g| g.region.clone()).collect();
+    for (chrom, regions) in convert_regions_vec_to_hashmap(compress_regions(&gene_regions)) {
+        index.entry(chrom).or_default().genes = regions;
+    }
+    for gene in genes {
+        index
+            .entry(gene.region.seqname.clone())
+            .or_default()
+            .gene_spans
+            .push(gene.clone());
+    }
+    index
+}
+
+impl ChromosomeRegions {
+    // Drops the exons and genes that do not overlap any of the sorted,
+    // non-overlapping targets
+    pub fn restrict_to(&mut self, targets: &[Region]) {
+        let overlaps = |region: &Region| overlap_length(&[(region.start, region.end)], targets) > 0;
+        self.exons.retain(overlaps);
+        self.genes.retain(overlaps);
+        self.gene_spans.retain(|gene| overlaps(&gene.region));
+    }
+}
+
+// Parses a region given as `chr`, `chr:start` or `chr:start-end`, with
+// 1-based, inclusive coordinates that may contain thousands separators. A
+// missing end extends to the end of the chromosome.
+pub fn parse_region(text: &str) -> Result<Region, Error> {
+    let whole = Region {
+        seqname: text.to_string(),
+        start: 0,
+        end: i64::MAX,
+    };
+    // Chromosome names may themselves contain ':' (e.g. HLA alleles), so a
+    // suffix that is not a valid position or range is taken as part of the
+    // name
+    let (seqname, range) = match text.rsplit_once(':') {
+        Some(split) => split,
+        None => return Ok(whole),
+    };
+    let parse = |pos: &str| pos.replace(',', "").parse::<i64>();
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => match (parse(start), parse(end)) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return Ok(whole),
+        },
+        None => match parse(range) {
+            Ok(start) => (start, i64::MAX),
+            Err(_) => return Ok(whole),
+        },
+    };
+    if start < 1 || end < start {
+        return Err(Error::msg(format!("Invalid region: {}", text)));
+    }
+    Ok(Region {
+        seqname: seqname.to_string(),
+        start: start - 1,
+        end,
+    })
+}
+
+// Sorts and merges target regions and groups them by chromosome
+pub fn group_targets(mut targets: Vec<Region>) -> HashMap<String, Vec<Region>> {
+    sort_regions_in_place(&mut targets);
+    convert_regions_vec_to_hashmap(compress_regions(&targets))
+}
+
+// Walks genes sorted by start alongside position-sorted reads, keeping the
+// genes that may overlap the current read. Genes may overlap each other, so
+// the cursor approach used for compressed regions does not apply.
+pub struct GeneCursor<'a> {
+    genes: &'a [Gene],
+    next: usize,
+    active: Vec<&'a Gene>,
+}
+
+impl<'a> GeneCursor<'a> {
+    pub fn new(genes: &'a [Gene]) -> Self {
+        GeneCursor {
+            genes,
+            next: 0,
+            active: vec![],
+        }
+    }
+
+    // Genes overlapping any of the read's aligned blocks. Reads must be
+    // visited in order of their start position.
+    pub fn overlapping(&mut self, blocks: &[(i64, i64)]) -> Vec<&'a Gene> {
+        let (read_start, read_end) = match (blocks.first(), blocks.last()) {
+            (Some(first), Some(last)) => (first.0, last.1),
+            _ => return vec![],
+        };
+        self.active.retain(|gene| gene.region.end > read_start);
+        while self.next < self.genes.len() && self.genes[self.next].region.start < read_end {
+            let gene = &self.genes[self.next];
+            if gene.region.end > read_start {
+                self.active.push(gene);
+            }
+            self.next += 1;
+        }
+        self.active
+            .iter()
+            .filter(|gene| {
+                blocks
+                    .iter()
+                    .any(|&(start, end)| start < gene.region.end && end > gene.region.start)
+            })
+            .copied()
+            .collect()
+    }
+}
+
+// Advances `index` past all regions that end at or before `pos`, returning the
+// new index. Used to walk sorted regions alongside position-sorted reads.
+pub fn advance_past(regions: &[Region], mut index: usize, pos: i64) -> usize {
+    while index < regions.len() && regions[index].end <= pos {
+        index += 1;
+    }
+    index
+}
+
+// Number of bases of the sorted, non-overlapping blocks that fall inside the
+// sorted, non-overlapping regions (as produced by compress_regions).
+pub fn overlap_length(blocks: &[(i64, i64)], regions: &[Region]) -> i64 {
+    let mut overlap = 0;
+    let mut index = match blocks.first() {
+        Some(&(start, _)) => regions.partition_point(|r| r.end <= start),
+        None => return 0,
+    };
+    for &(start, end) in blocks {
+        while index < regions.len() && regions[index].end <= start {
+            index += 1;
+        }
+        for region in &regions[index..] {
+            if region.start >= end {
+                break;
+            }
+            overlap += end.min(region.end) - start.max(region.start);
+        }
+    }
+    overlap
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -195,4 +383,140 @@ mod tests {
         assert_eq!(regions_map.get("chr1").unwrap().len(), 2);
         assert_eq!(regions_map.get("chr2").unwrap().len(), 1);
     }
+
+    #[test]
+    fn test_overlap_length() {
+        let regions = vec![
+            Region {
+                seqname: "chr1".to_string(),
+                start: 100,
+                end: 200,
+            },
+            Region {
+                seqname: "chr1".to_string(),
+                start: 300,
+                end: 400,
+            },
+        ];
+        assert_eq!(overlap_length(&[], &regions), 0);
+        assert_eq!(overlap_length(&[(0, 100)], &regions), 0);
+        assert_eq!(overlap_length(&[(150, 250)], &regions), 50);
+        assert_eq!(overlap_length(&[(150, 350)], &regions), 100);
+        assert_eq!(
+            overlap_length(&[(90, 110), (190, 210), (390, 500)], &regions),
+            30
+        );
+    }
+
+    #[test]
+    fn test_compress_regions_empty() {
+        assert!(compress_regions(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_compress_regions_contained() {
+        let regions = vec![
+            Region {
+                seqname: "chr1".to_string(),
+                start: 100,
+                end: 300,
+            },
+            Region {
+                seqname: "chr1".to_string(),
+                start: 150,
+                end: 200,
+            },
+        ];
+        let compressed = compress_regions(&regions);
+        assert_eq!(compressed.len(), 1);
+        assert_eq!(compressed[0].end, 300);
+    }
+
+    #[test]
+    fn test_advance_past() {
+        let regions = vec![
+            Region {
+                seqname: "chr1".to_string(),
+                start: 100,
+                end: 200,
+            },
+            Region {
+                seqname: "chr1".to_string(),
+                start: 300,
+                end: 400,
+            },
+        ];
+        assert_eq!(advance_past(&regions, 0, 50), 0);
+        assert_eq!(advance_past(&regions, 0, 199), 0);
+        assert_eq!(advance_past(&regions, 0, 200), 1);
+        assert_eq!(advance_past(&regions, 1, 500), 2);
+    }
+
+    #[test]
+    fn test_merge_intervals() {
+        let merged = merge_intervals(vec![(500, 600), (100, 200), (150, 300), (300, 400)]);
+        assert_eq!(merged, vec![(100, 400), (500, 600)]);
+    }
+
+    #[test]
+    fn test_parse_region() {
+        let region = parse_region("chr1:1,000,000-2,000,000").unwrap();
+        assert_eq!(region.seqname, "chr1");
+        assert_eq!((region.start, region.end), (999_999, 2_000_000));
+        let region = parse_region("chrX").unwrap();
+        assert_eq!((region.start, region.end), (0, i64::MAX));
+        let region = parse_region("chr2:500").unwrap();
+        assert_eq!((region.start, region.end), (499, i64::MAX));
+        let region = parse_region("HLA-A*01:01N").unwrap();
+        assert_eq!(region.seqname, "HLA-A*01:01N");
+        assert!(parse_region("chr1:200-100").is_err());
+        assert!(parse_region("chr1:0-100").is_err());
+    }
+
+    #[test]
+    fn test_restrict_to_targets() {
+        let region = |start, end| Region {
+            seqname: "chr1".to_string(),
+            start,
+            end,
+        };
+        let mut regions = ChromosomeRegions {
+            exons: vec![region(100, 200), region(500, 600), region(900, 1000)],
+            genes: vec![region(50, 1000)],
+            gene_spans: vec![],
+        };
+        let targets = group_targets(vec![region(550, 560), region(150, 160), region(155, 170)]);
+        assert_eq!(targets["chr1"].len(), 2);
+        regions.restrict_to(&targets["chr1"]);
+        let starts: Vec<_> = regions.exons.iter().map(|r| r.start).collect();
+        assert_eq!(starts, vec![100, 500]);
+        assert_eq!(regions.genes.len(), 1);
+    }
+
+    #[test]
+    fn test_gene_cursor_overlapping() {
+        let gene = |index, start, end| Gene {
+            id: format!("gene{}", index),
+            index,
+            biotype: None,
+            exons: vec![(start, end)],
+            region: Region {
+                seqname: "chr1".to_string(),
+                start,
+                end,
+            },
+        };
+        let genes = vec![gene(0, 100, 1000), gene(1, 200, 300), gene(2, 500, 600)];
+        let mut cursor = GeneCursor::new(&genes);
+        let ids = |hits: Vec<&Gene>| hits.iter().map(|g| g.index).collect::<Vec<_>>();
+        assert_eq!(ids(cursor.overlapping(&[(50, 99)])), Vec::<usize>::new());
+        assert_eq!(ids(cursor.overlapping(&[(250, 260)])), vec![0, 1]);
+        // Spliced read skipping over gene 2
+        assert_eq!(ids(cursor.overlapping(&[(400, 450), (700, 750)])), vec![0]);
+        assert_eq!(ids(cursor.overlapping(&[(550, 560)])), vec![0, 2]);
+        assert_eq!(
+            ids(cursor.overlapping(&[(1500, 1600)])),
+            Vec::<usize>::new()
+        );
+    }
 }
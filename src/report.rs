@@ -0,0 +1,431 @@
+use crate::barcodes::BarcodeCounts;
+use crate::chimeric::ChimericReport;
+use crate::chromosomes::ChromosomeReport;
+use crate::classify::Classification;
+use crate::coverage::CoverageTable;
+use crate::distributions::{DistributionSet, ReadDistributions};
+use crate::introns::IntronTable;
+use crate::normalise::GeneExpression;
+use crate::read_groups::ReadGroupReport;
+use crate::saturation::SaturationReport;
+use crate::spike_ins::SpikeInReport;
+use crate::transcripts::{EquivalenceClassTable, TranscriptCompatibility};
+use crate::{CategoryCounts, CountResult, Preset, UmiMethod};
+use anyhow::Error;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(Debug, Serialize)]
+pub struct ReportOptions {
+    pub minmapqual: u8,
+    pub required_flag: u16,
+    pub filtered_flag: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expression: Option<String>,
+    pub preset: Option<Preset>,
+    pub preset_detected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_exon_fraction: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub umi: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub umi_method: Option<UmiMethod>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subsample_fraction: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subsample_reads: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subsample_seed: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub targets: Option<PathBuf>,
+    pub gtf: PathBuf,
+    pub bamfile: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CountReport {
+    pub options: ReportOptions,
+    #[serde(flatten)]
+    pub categories: CategoryCounts,
+    // Mapped reads rejected by the filter expression alone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expression_rejected: Option<usize>,
+    pub mapped: CountResult,
+    // Not counted with --skip-unmapped
+    pub unmapped: Option<CountResult>,
+    // Reads on spike-ins, which are in the total but not mapped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spike_ins: Option<SpikeInReport>,
+    pub total: CountResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distributions: Option<DistributionSet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deduplicated: Option<CategoryCounts>,
+    pub duplicates: CategoryCounts,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_duplicates: Option<CategoryCounts>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saturation: Option<SaturationReport>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub chromosomes: Vec<ChromosomeReport>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub classifications: Vec<Classification>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub read_groups: Vec<ReadGroupReport>,
+    // Accepted reads per cell barcode, written as a separate table
+    #[serde(skip)]
+    pub barcodes: Option<HashMap<Vec<u8>, BarcodeCounts>>,
+    // Normalised expression per gene, written as a separate table
+    #[serde(skip)]
+    pub genes: Option<Vec<GeneExpression>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chimeric: Option<ChimericReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transcript_compatibility: Option<TranscriptCompatibility>,
+    // Accepted reads per equivalence class, written as a separate table
+    #[serde(skip)]
+    pub equivalence_classes: Option<EquivalenceClassTable>,
+    // Spliced and retained reads per intron, written as a separate table
+    #[serde(skip)]
+    pub introns: Option<IntronTable>,
+    // Depth summary per merged exon region, written as a separate table
+    #[serde(skip)]
+    pub coverage: Option<CoverageTable>,
+}
+
+fn write_count_row(
+    out: &mut impl Write,
+    category: &str,
+    counts: &CountResult,
+) -> Result<(), Error> {
+    writeln!(
+        out,
+        "{}\t{}\t{}\t{}",
+        category,
+        counts.accepted,
+        counts.rejected,
+        counts.accepted + counts.rejected
+    )?;
+    Ok(())
+}
+
+// One row for each category of the counts
+fn write_category_rows(out: &mut impl Write, categories: &CategoryCounts) -> Result<(), Error> {
+    for (category, counts) in categories.iter() {
+        write_count_row(out, category, counts)?;
+    }
+    Ok(())
+}
+
+fn write_distribution_summary(
+    out: &mut impl Write,
+    class: &str,
+    distributions: &ReadDistributions,
+) -> Result<(), Error> {
+    for (name, hist) in distributions.named() {
+        let mean = hist
+            .mean()
+            .map_or("NA".to_string(), |m| format!("{:.2}", m));
+        let median = hist.median().map_or("NA".to_string(), |m| m.to_string());
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}",
+            name,
+            class,
+            hist.total(),
+            mean,
+            median
+        )?;
+    }
+    Ok(())
+}
+
+fn write_distribution_values(
+    out: &mut impl Write,
+    class: &str,
+    distributions: &ReadDistributions,
+) -> Result<(), Error> {
+    for (name, hist) in distributions.named() {
+        for (value, count) in hist.iter() {
+            writeln!(out, "{}\t{}\t{}\t{}", name, class, value, count)?;
+        }
+    }
+    Ok(())
+}
+
+impl CountReport {
+    pub fn write_tsv(&self, out: &mut impl Write) -> Result<(), Error> {
+        writeln!(out, "## Min mapping quality: {}", self.options.minmapqual)?;
+        writeln!(out, "## Required flag: {}", self.options.required_flag)?;
+        writeln!(out, "## Filtered flag: {}", self.options.filtered_flag)?;
+        if let Some(expression) = &self.options.expression {
+            writeln!(out, "## Filter expression: {}", expression)?;
+        }
+        if let Some(preset) = self.options.preset {
+            let name = preset.to_possible_value().map(|v| v.get_name().to_string());
+            writeln!(
+                out,
+                "## Preset: {}{}",
+                name.unwrap_or_default(),
+                if self.options.preset_detected {
+                    " (detected)"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        if let Some(fraction) = self.options.min_exon_fraction {
+            writeln!(out, "## Min exon fraction: {}", fraction)?;
+        }
+        if let (Some(umi), Some(method)) = (&self.options.umi, self.options.umi_method) {
+            let method = method.to_possible_value().map(|v| v.get_name().to_string());
+            writeln!(out, "## UMI: {} ({})", umi, method.unwrap_or_default())?;
+        }
+        if let (Some(fraction), Some(seed)) =
+            (self.options.subsample_fraction, self.options.subsample_seed)
+        {
+            match self.options.subsample_reads {
+                Some(reads) => writeln!(
+                    out,
+                    "## Subsample: {} of templates for {} reads (seed {})",
+                    fraction, reads, seed
+                )?,
+                None => writeln!(
+                    out,
+                    "## Subsample: {} of templates (seed {})",
+                    fraction, seed
+                )?,
+            }
+        }
+        if !self.options.regions.is_empty() {
+            writeln!(out, "## Regions: {}", self.options.regions.join(" "))?;
+        }
+        if let Some(targets) = &self.options.targets {
+            writeln!(out, "## Targets: {}", targets.display())?;
+        }
+        writeln!(out, "## GTF file: {}", self.options.gtf.display())?;
+        writeln!(out, "## BAM file: {}", self.options.bamfile.display())?;
+        writeln!(out, "#Category\tAccepted\tRejected\tTotal")?;
+        write_category_rows(out, &self.categories)?;
+        write_count_row(out, "Mapped", &self.mapped)?;
+        if let Some(spike_ins) = &self.spike_ins {
+            write_count_row(out, "SpikeIn", &spike_ins.total)?;
+        }
+        if let Some(unmapped) = &self.unmapped {
+            write_count_row(out, "Unmapped", unmapped)?;
+        }
+        write_count_row(out, "Total", &self.total)?;
+
+        if let Some(spike_ins) = &self.spike_ins {
+            writeln!(out)?;
+            writeln!(out, "#SpikeIn\tAccepted\tRejected\tTotal\tConcentration")?;
+            for spike_in in &spike_ins.spike_ins {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}",
+                    spike_in.name,
+                    spike_in.counts.accepted,
+                    spike_in.counts.rejected,
+                    spike_in.counts.total(),
+                    spike_in
+                        .concentration
+                        .map_or("NA".to_string(), |c| c.to_string())
+                )?;
+            }
+            writeln!(out)?;
+            writeln!(out, "#SpikeInSummary\tValue")?;
+            let ratio = spike_ins
+                .endogenous_ratio
+                .map_or("NA".to_string(), |r| format!("{:.4}", r));
+            writeln!(out, "EndogenousToSpikeInRatio\t{}", ratio)?;
+            if let Some(fit) = &spike_ins.fit {
+                writeln!(out, "FitPoints\t{}", fit.points)?;
+                writeln!(out, "FitSlope\t{:.4}", fit.slope)?;
+                writeln!(out, "FitIntercept\t{:.4}", fit.intercept)?;
+                writeln!(out, "FitRSquared\t{:.4}", fit.r_squared)?;
+            }
+        }
+
+        for group in &self.read_groups {
+            writeln!(out)?;
+            writeln!(out, "#ReadGroup:{}\tAccepted\tRejected\tTotal", group.id)?;
+            write_category_rows(out, &group.categories)?;
+            write_count_row(out, "Mapped", &group.mapped)?;
+            if let Some(unmapped) = &group.unmapped {
+                write_count_row(out, "Unmapped", unmapped)?;
+            }
+            write_count_row(out, "Total", &group.total)?;
+        }
+
+        if !self.chromosomes.is_empty() {
+            writeln!(out)?;
+            write!(out, "#Chromosome\tLength")?;
+            for label in self.categories.labels() {
+                write!(out, "\t{}Accepted\t{}Rejected", label, label)?;
+            }
+            writeln!(out, "\tMappedAccepted\tMappedRejected\tReadsPerMb")?;
+            for chrom in &self.chromosomes {
+                write!(out, "{}\t{}", chrom.name, chrom.length)?;
+                for (_, counts) in chrom.categories.iter() {
+                    write!(out, "\t{}\t{}", counts.accepted, counts.rejected)?;
+                }
+                writeln!(
+                    out,
+                    "\t{}\t{}\t{}",
+                    chrom.mapped.accepted,
+                    chrom.mapped.rejected,
+                    chrom
+                        .reads_per_mb
+                        .map_or("NA".to_string(), |r| format!("{:.2}", r))
+                )?;
+            }
+        }
+
+        if let (Some(expression), Some(rejected)) =
+            (&self.options.expression, self.expression_rejected)
+        {
+            writeln!(out)?;
+            writeln!(out, "#FilterExpression\tRejected")?;
+            writeln!(out, "{}\t{}", expression, rejected)?;
+        }
+
+        if let Some(dedup) = &self.deduplicated {
+            writeln!(out)?;
+            writeln!(out, "#Deduplicated\tAccepted\tRejected\tTotal")?;
+            write_category_rows(out, dedup)?;
+            write_count_row(out, "Mapped", &dedup.mapped())?;
+        }
+
+        // Files that are not duplicate-marked would only show a table of zeros
+        if self.duplicates.mapped().total() > 0 || self.estimated_duplicates.is_some() {
+            writeln!(out)?;
+            self.write_duplicate_rows(out, "Duplicates", &self.duplicates)?;
+        }
+        if let Some(estimated) = &self.estimated_duplicates {
+            writeln!(out)?;
+            self.write_duplicate_rows(out, "EstimatedDuplicates", estimated)?;
+        }
+
+        if let Some(chimeric) = &self.chimeric {
+            writeln!(out)?;
+            writeln!(out, "#Chimeric\tReads")?;
+            writeln!(out, "Intrachromosomal\t{}", chimeric.intrachromosomal)?;
+            writeln!(out, "Interchromosomal\t{}", chimeric.interchromosomal)?;
+            writeln!(out, "StrandSwitching\t{}", chimeric.strand_switching)?;
+            writeln!(out, "Total\t{}", chimeric.reads)?;
+            writeln!(out, "FractionOfMapped\t{:.6}", chimeric.fraction)?;
+            if !chimeric.gene_pairs.is_empty() {
+                writeln!(out)?;
+                writeln!(out, "#ChimericGene1\tChimericGene2\tReads")?;
+                for pair in &chimeric.gene_pairs {
+                    writeln!(out, "{}\t{}\t{}", pair.gene1, pair.gene2, pair.reads)?;
+                }
+            }
+        }
+
+        if let Some(compatibility) = &self.transcript_compatibility {
+            writeln!(out)?;
+            writeln!(out, "#TranscriptCompatibility\tFragments")?;
+            writeln!(out, "Compatible\t{}", compatibility.compatible)?;
+            writeln!(out, "Incompatible\t{}", compatibility.incompatible)?;
+            writeln!(
+                out,
+                "EquivalenceClasses\t{}",
+                compatibility.equivalence_classes
+            )?;
+        }
+
+        for classification in &self.classifications {
+            writeln!(out)?;
+            writeln!(out, "#{}\tAccepted\tRejected\tTotal", classification.name)?;
+            for label in &classification.labels {
+                write_count_row(out, &label.label, &label.counts)?;
+            }
+        }
+
+        if let Some(distributions) = &self.distributions {
+            writeln!(out)?;
+            writeln!(out, "#Distribution\tClass\tReads\tMean\tMedian")?;
+            write_distribution_summary(out, "Accepted", &distributions.accepted)?;
+            write_distribution_summary(out, "Rejected", &distributions.rejected)?;
+            writeln!(out)?;
+            writeln!(out, "#Distribution\tClass\tValue\tCount")?;
+            write_distribution_values(out, "Accepted", &distributions.accepted)?;
+            write_distribution_values(out, "Rejected", &distributions.rejected)?;
+        }
+
+        if let Some(saturation) = &self.saturation {
+            writeln!(out)?;
+            writeln!(
+                out,
+                "#SubsampleFraction\tReads\tExonReads\tGenes\tDistinctFragments"
+            )?;
+            for point in &saturation.curve {
+                writeln!(
+                    out,
+                    "{:.2}\t{}\t{}\t{}\t{}",
+                    point.fraction,
+                    point.reads,
+                    point.exon_reads,
+                    point.genes,
+                    point.distinct_fragments
+                )?;
+            }
+            writeln!(out)?;
+            writeln!(out, "#ExtrapolatedDepth\tFragments\tDistinctFragments")?;
+            for point in &saturation.extrapolation {
+                writeln!(
+                    out,
+                    "{}\t{:.0}\t{:.0}",
+                    point.depth, point.fragments, point.distinct_fragments
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Duplicate counts per category, with the fraction of all reads in the
+    // category that are duplicates
+    fn write_duplicate_rows(
+        &self,
+        out: &mut impl Write,
+        title: &str,
+        duplicates: &CategoryCounts,
+    ) -> Result<(), Error> {
+        writeln!(out, "#{}\tAccepted\tRejected\tTotal\tFraction", title)?;
+        let mapped = duplicates.mapped();
+        let rows = duplicates
+            .iter()
+            .zip(self.categories.iter())
+            .map(|((category, dups), (_, all))| (category, dups, all))
+            .chain([("Mapped", &mapped, &self.mapped)]);
+        for (category, dups, all) in rows {
+            let fraction = if all.total() == 0 {
+                0.0
+            } else {
+                dups.total() as f64 / all.total() as f64
+            };
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{:.4}",
+                category,
+                dups.accepted,
+                dups.rejected,
+                dups.total(),
+                fraction
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write_json(&self, out: &mut impl Write) -> Result<(), Error> {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)?;
+        Ok(())
+    }
+}
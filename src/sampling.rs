@@ -0,0 +1,94 @@
+use crate::reference::open_indexed_bam;
+use anyhow::Error;
+use std::path::Path;
+
+// Maps a read name to a value in [0, 1) that is uniformly distributed over
+// read names and depends only on the name and seed, so that both mates of a
+// template always get the same value.
+pub fn read_name_fraction(qname: &[u8], seed: u64) -> f64 {
+    // FNV-1a over the name, followed by the splitmix64 finaliser to spread
+    // similar names across the whole range
+    let mut hash: u64 = 0xcbf29ce484222325 ^ seed;
+    for &byte in qname {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^= hash >> 31;
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Keeps a fixed fraction of templates, chosen by hashing their read names
+#[derive(Debug, Clone, Copy)]
+pub struct Subsample {
+    pub fraction: f64,
+    pub seed: u64,
+}
+
+impl Subsample {
+    pub fn keeps(&self, qname: &[u8]) -> bool {
+        read_name_fraction(qname, self.seed) < self.fraction
+    }
+}
+
+// The fraction of records to keep for about `reads` records in total, from
+// the mapped and unmapped counts in the BAM index. The counts include
+// secondary and supplementary alignments, so the subsample can be smaller
+// than asked for.
+pub fn fraction_for_reads(
+    bamfile: &Path,
+    reference: Option<&Path>,
+    reads: u64,
+) -> Result<f64, Error> {
+    let mut bam = open_indexed_bam(bamfile, reference)?;
+    let total: u64 = bam
+        .index_stats()?
+        .iter()
+        .map(|(_, _, mapped, unmapped)| mapped + unmapped)
+        .sum();
+    if total == 0 {
+        return Ok(1.0);
+    }
+    Ok((reads as f64 / total as f64).min(1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_name_fraction_deterministic() {
+        let a = read_name_fraction(b"A00123:8:H7:1:1101:1000:1000", 0);
+        assert_eq!(a, read_name_fraction(b"A00123:8:H7:1:1101:1000:1000", 0));
+        assert_ne!(a, read_name_fraction(b"A00123:8:H7:1:1101:1000:1000", 1));
+        assert!((0.0..1.0).contains(&a));
+    }
+
+    #[test]
+    fn test_read_name_fraction_uniform() {
+        let n = 10_000;
+        let below_half = (0..n)
+            .filter(|i| read_name_fraction(format!("read{}", i).as_bytes(), 42) < 0.5)
+            .count();
+        assert!((4_700..5_300).contains(&below_half));
+    }
+
+    #[test]
+    fn test_subsample_keeps_mates_together() {
+        let subsample = Subsample {
+            fraction: 0.25,
+            seed: 7,
+        };
+        let kept = (0..1000)
+            .filter(|i| subsample.keeps(format!("read{}", i).as_bytes()))
+            .count();
+        assert!((200..300).contains(&kept));
+        assert_eq!(subsample.keeps(b"read1"), subsample.keeps(b"read1"));
+        let all = Subsample {
+            fraction: 1.0,
+            seed: 7,
+        };
+        assert!(all.keeps(b"read1"));
+    }
+}
@@ -0,0 +1,288 @@
+use crate::duplicates::FragmentKey;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+// Reads are assigned to one of this many equally sized bins by a hash of
+// their name. A subsample of fraction k / SUBSAMPLE_BINS is the reads in
+// the first k bins, so both mates of a pair are always sampled together.
+pub const SUBSAMPLE_BINS: usize = 20;
+
+// Sequencing depths, as multiples of the observed depth, at which the
+// number of distinct fragments is extrapolated
+pub const EXTRAPOLATION_MULTIPLES: [f64; 5] = [1.0, 2.0, 3.0, 5.0, 10.0];
+
+pub fn subsample_bin(fraction: f64) -> usize {
+    ((fraction * SUBSAMPLE_BINS as f64) as usize).min(SUBSAMPLE_BINS - 1)
+}
+
+// What an accepted read contributes to the saturation curve
+#[derive(Debug, Clone)]
+pub struct SaturationRead {
+    pub bin: usize,
+    // Only the first mate of a pair records the fragment, so that each
+    // fragment is counted once
+    pub fragment: Option<FragmentKey>,
+    // Indices of the genes the read overlaps
+    pub genes: Vec<usize>,
+}
+
+// Reads, exonic reads, genes and fragments collected for the saturation
+// curve. Fragments are held until no later read can share their signature,
+// then reduced to how many times each was seen and the first bin it was
+// seen in.
+#[derive(Debug, Default)]
+pub struct SaturationCounts {
+    reads: [usize; SUBSAMPLE_BINS],
+    exon_reads: [usize; SUBSAMPLE_BINS],
+    // First bin in which each gene has an exonic read
+    gene_bins: HashMap<usize, usize>,
+    // Number of times each fragment was seen, and its first bin
+    pending: BTreeMap<FragmentKey, (usize, usize)>,
+    fragment_bins: [usize; SUBSAMPLE_BINS],
+    // Number of fragments seen exactly j times, keyed by j
+    counts_of_counts: BTreeMap<usize, usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SaturationPoint {
+    pub fraction: f64,
+    pub reads: usize,
+    pub exon_reads: usize,
+    pub genes: usize,
+    pub distinct_fragments: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtrapolationPoint {
+    pub depth: f64,
+    pub fragments: f64,
+    pub distinct_fragments: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SaturationReport {
+    pub curve: Vec<SaturationPoint>,
+    pub extrapolation: Vec<ExtrapolationPoint>,
+}
+
+impl SaturationCounts {
+    pub fn add(&mut self, read: SaturationRead, exonic: bool) {
+        self.reads[read.bin] += 1;
+        if exonic {
+            self.exon_reads[read.bin] += 1;
+            for gene in read.genes {
+                let bin = self.gene_bins.entry(gene).or_insert(read.bin);
+                *bin = (*bin).min(read.bin);
+            }
+        }
+        if let Some(fragment) = read.fragment {
+            let (count, bin) = self.pending.entry(fragment).or_insert((0, read.bin));
+            *count += 1;
+            *bin = (*bin).min(read.bin);
+        }
+    }
+
+    pub fn merge(&mut self, other: SaturationCounts) {
+        for bin in 0..SUBSAMPLE_BINS {
+            self.reads[bin] += other.reads[bin];
+            self.exon_reads[bin] += other.exon_reads[bin];
+            self.fragment_bins[bin] += other.fragment_bins[bin];
+        }
+        for (gene, bin) in other.gene_bins {
+            let current = self.gene_bins.entry(gene).or_insert(bin);
+            *current = (*current).min(bin);
+        }
+        for (fragment, (count, bin)) in other.pending {
+            let (current_count, current_bin) = self.pending.entry(fragment).or_insert((0, bin));
+            *current_count += count;
+            *current_bin = (*current_bin).min(bin);
+        }
+        for (times, fragments) in other.counts_of_counts {
+            *self.counts_of_counts.entry(times).or_insert(0) += fragments;
+        }
+    }
+
+    // Completes the fragments before `boundary`, or all fragments if there
+    // is none
+    pub fn flush_fragments(&mut self, boundary: Option<FragmentKey>) {
+        let complete = match boundary {
+            Some(boundary) => {
+                match self.pending.first_key_value() {
+                    Some((first, _)) if *first < boundary => {}
+                    _ => return,
+                }
+                let kept = self.pending.split_off(&boundary);
+                std::mem::replace(&mut self.pending, kept)
+            }
+            None => std::mem::take(&mut self.pending),
+        };
+        for (count, bin) in complete.into_values() {
+            self.fragment_bins[bin] += 1;
+            *self.counts_of_counts.entry(count).or_insert(0) += 1;
+        }
+    }
+
+    pub fn curve(&self) -> Vec<SaturationPoint> {
+        let mut points = vec![];
+        let (mut reads, mut exon_reads, mut fragments) = (0, 0, 0);
+        for bin in 0..SUBSAMPLE_BINS {
+            reads += self.reads[bin];
+            exon_reads += self.exon_reads[bin];
+            fragments += self.fragment_bins[bin];
+            points.push(SaturationPoint {
+                fraction: (bin + 1) as f64 / SUBSAMPLE_BINS as f64,
+                reads,
+                exon_reads,
+                genes: self.gene_bins.values().filter(|&&b| b <= bin).count(),
+                distinct_fragments: fragments,
+            });
+        }
+        points
+    }
+
+    pub fn extrapolate(&self, multiples: &[f64]) -> Vec<ExtrapolationPoint> {
+        let fragments: usize = self.counts_of_counts.iter().map(|(j, n)| j * n).sum();
+        let distinct: usize = self.counts_of_counts.values().sum();
+        multiples
+            .iter()
+            .map(|&depth| ExtrapolationPoint {
+                depth,
+                fragments: fragments as f64 * depth,
+                distinct_fragments: distinct as f64
+                    + unseen_fragments(&self.counts_of_counts, depth - 1.0),
+            })
+            .collect()
+    }
+
+    pub fn report(&self) -> SaturationReport {
+        SaturationReport {
+            curve: self.curve(),
+            extrapolation: self.extrapolate(&EXTRAPOLATION_MULTIPLES),
+        }
+    }
+}
+
+// P(X >= j) for X ~ Binomial(n, p), for j = 0..=n
+fn binomial_tail(n: usize, p: f64) -> Vec<f64> {
+    let mut pmf = vec![0.0; n + 1];
+    pmf[0] = (1.0 - p).powi(n as i32);
+    for k in 1..=n {
+        pmf[k] = pmf[k - 1] * (n - k + 1) as f64 / k as f64 * p / (1.0 - p);
+    }
+    let mut tail = vec![0.0; n + 2];
+    for k in (0..=n).rev() {
+        tail[k] = tail[k + 1] + pmf[k];
+    }
+    tail.truncate(n + 1);
+    tail
+}
+
+// Expected number of fragments not yet seen that would be seen after
+// sequencing `t` times as many reads again, from the number of fragments
+// seen exactly j times. Uses the Good-Toulmin estimator, which is exact in
+// expectation up to t = 1, smoothed with binomial weights beyond that as in
+// Orlitsky, Suresh and Wu (2016) so that it does not diverge. Like Preseq,
+// the estimate is only reliable up to about ten times the observed depth.
+pub fn unseen_fragments(counts_of_counts: &BTreeMap<usize, usize>, t: f64) -> f64 {
+    if t <= 0.0 {
+        return 0.0;
+    }
+    let reads: usize = counts_of_counts.iter().map(|(j, n)| j * n).sum();
+    let weights = if t > 1.0 {
+        let n = (0.5 * (reads as f64 * t * t / (t - 1.0)).log(3.0))
+            .ceil()
+            .max(1.0) as usize;
+        binomial_tail(n, 2.0 / (t + 2.0))
+    } else {
+        vec![]
+    };
+    let mut unseen = 0.0;
+    for (&j, &n) in counts_of_counts {
+        let weight = if t > 1.0 {
+            match weights.get(j) {
+                Some(w) => *w,
+                None => break,
+            }
+        } else {
+            1.0
+        };
+        let sign = if j % 2 == 1 { 1.0 } else { -1.0 };
+        unseen += sign * t.powi(j as i32) * weight * n as f64;
+    }
+    unseen.max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(start: i64) -> FragmentKey {
+        FragmentKey {
+            tid: 0,
+            start,
+            end: start + 300,
+            reverse: false,
+            second_in_pair: false,
+        }
+    }
+
+    fn read(bin: usize, start: i64, genes: Vec<usize>) -> SaturationRead {
+        SaturationRead {
+            bin,
+            fragment: Some(fragment(start)),
+            genes,
+        }
+    }
+
+    #[test]
+    fn test_saturation_curve() {
+        let mut counts = SaturationCounts::default();
+        counts.add(read(0, 100, vec![0]), true);
+        counts.add(read(5, 100, vec![0, 1]), true);
+        counts.add(read(10, 500, vec![]), false);
+        counts.flush_fragments(Some(FragmentKey::first_at(0, 200)));
+        let mut other = SaturationCounts::default();
+        other.add(read(19, 900, vec![2]), true);
+        counts.merge(other);
+        counts.flush_fragments(None);
+
+        let curve = counts.curve();
+        assert_eq!(curve.len(), SUBSAMPLE_BINS);
+        assert_eq!(
+            (curve[0].reads, curve[0].exon_reads, curve[0].genes),
+            (1, 1, 1)
+        );
+        assert_eq!(curve[0].distinct_fragments, 1);
+        assert_eq!((curve[5].reads, curve[5].genes), (2, 2));
+        assert_eq!(curve[5].distinct_fragments, 1);
+        assert_eq!(curve[10].distinct_fragments, 2);
+        let last = curve.last().unwrap();
+        assert_eq!(last.fraction, 1.0);
+        assert_eq!((last.reads, last.exon_reads, last.genes), (4, 3, 3));
+        assert_eq!(last.distinct_fragments, 3);
+    }
+
+    #[test]
+    fn test_unseen_fragments() {
+        // All fragments seen twice: the library is close to saturated
+        let saturated = BTreeMap::from([(2, 1000)]);
+        assert_eq!(unseen_fragments(&saturated, 1.0), 0.0);
+        // All fragments seen once: doubling depth finds as many again
+        let unsaturated = BTreeMap::from([(1, 1000)]);
+        assert_eq!(unseen_fragments(&unsaturated, 1.0), 1000.0);
+        assert_eq!(unseen_fragments(&unsaturated, 0.0), 0.0);
+        // Smoothed estimates keep growing with depth without diverging
+        let mixed = BTreeMap::from([(1, 600), (2, 200), (3, 50), (4, 10)]);
+        let at_2 = unseen_fragments(&mixed, 1.0);
+        let at_5 = unseen_fragments(&mixed, 4.0);
+        let at_10 = unseen_fragments(&mixed, 9.0);
+        assert!(at_2 > 0.0 && at_2 < at_5 && at_5 < at_10);
+        assert!(at_10 < 600.0 * 9.0);
+    }
+
+    #[test]
+    fn test_binomial_tail() {
+        let tail = binomial_tail(2, 0.5);
+        assert_eq!(tail, vec![1.0, 0.75, 0.25]);
+    }
+}
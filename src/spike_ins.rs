@@ -0,0 +1,219 @@
+use crate::cigar::cigar_blocks;
+use crate::classify::{FeatureClassifier, ReadClassifier};
+use crate::io::GtfFile;
+use crate::CountResult;
+use anyhow::Error;
+use rust_htslib::bam::Record;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Spike-in controls, declared as whole contigs (one spike-in per contig) or
+// as the genes of a separate GTF file. Reads on spike-ins are counted apart
+// from the endogenous reads.
+#[derive(Debug, Clone)]
+pub struct SpikeIns {
+    names: Vec<String>,
+    contigs: HashMap<String, usize>,
+    // Genes of the spike-in GTF; their labels follow the contigs in `names`
+    genes: Option<FeatureClassifier>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SpikeInCounts {
+    pub name: String,
+    #[serde(flatten)]
+    pub counts: CountResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concentration: Option<f64>,
+}
+
+// Least-squares fit of log10 accepted reads against log10 concentration,
+// over the spike-ins with both. A slope close to 1 means counts are
+// proportional to input amount.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpikeInFit {
+    pub points: usize,
+    pub slope: f64,
+    pub intercept: f64,
+    pub r_squared: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SpikeInReport {
+    pub spike_ins: Vec<SpikeInCounts>,
+    pub total: CountResult,
+    // Accepted endogenous mapped reads per accepted spike-in read
+    pub endogenous_ratio: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fit: Option<SpikeInFit>,
+}
+
+impl SpikeIns {
+    pub fn new(contigs: &[String], gtf: Option<&Path>) -> Result<Self, Error> {
+        let mut names = contigs.to_vec();
+        let genes = match gtf {
+            Some(path) => {
+                let (_, genes) = GtfFile::new(path).exon_and_gene_regions()?;
+                let genes = FeatureClassifier::by_gene(&genes);
+                names.extend(genes.labels().iter().cloned());
+                Some(genes)
+            }
+            None => None,
+        };
+        Ok(SpikeIns {
+            contigs: contigs
+                .iter()
+                .enumerate()
+                .map(|(i, contig)| (contig.clone(), i))
+                .collect(),
+            names,
+            genes,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn name(&self, index: usize) -> &str {
+        &self.names[index]
+    }
+
+    // The spike-in a read on `chrom` belongs to: the contig itself, or the
+    // first spike-in gene whose exons the read overlaps
+    pub fn find(&self, chrom: &str, read: &Record) -> Option<usize> {
+        if let Some(&index) = self.contigs.get(chrom) {
+            return Some(index);
+        }
+        let genes = self.genes.as_ref()?;
+        let mut classes = vec![];
+        genes.classify(chrom, read, &cigar_blocks(read), &mut classes);
+        classes.first().map(|class| self.contigs.len() + class)
+    }
+
+    pub fn report(
+        &self,
+        counts: &[CountResult],
+        endogenous: &CountResult,
+        concentrations: Option<&HashMap<String, f64>>,
+    ) -> SpikeInReport {
+        let spike_ins: Vec<SpikeInCounts> = self
+            .names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| SpikeInCounts {
+                name: name.clone(),
+                counts: counts.get(i).cloned().unwrap_or_default(),
+                concentration: concentrations.and_then(|c| c.get(name).copied()),
+            })
+            .collect();
+        let mut total = CountResult::default();
+        for spike_in in &spike_ins {
+            total.merge(&spike_in.counts);
+        }
+        let endogenous_ratio =
+            (total.accepted > 0).then(|| endogenous.accepted as f64 / total.accepted as f64);
+        let points: Vec<(f64, f64)> = spike_ins
+            .iter()
+            .filter_map(|spike_in| match spike_in.concentration {
+                Some(concentration) if concentration > 0.0 && spike_in.counts.accepted > 0 => {
+                    Some((
+                        concentration.log10(),
+                        (spike_in.counts.accepted as f64).log10(),
+                    ))
+                }
+                _ => None,
+            })
+            .collect();
+        SpikeInReport {
+            spike_ins,
+            total,
+            endogenous_ratio,
+            fit: fit_line(&points),
+        }
+    }
+}
+
+// Ordinary least squares of y on x. Needs two distinct x values.
+fn fit_line(points: &[(f64, f64)]) -> Option<SpikeInFit> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    let syy: f64 = points.iter().map(|(_, y)| (y - mean_y).powi(2)).sum();
+    let sxy: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    if sxx == 0.0 {
+        return None;
+    }
+    let slope = sxy / sxx;
+    Some(SpikeInFit {
+        points: points.len(),
+        slope,
+        intercept: mean_y - slope * mean_x,
+        r_squared: if syy == 0.0 {
+            1.0
+        } else {
+            sxy * sxy / (sxx * syy)
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(accepted: usize) -> CountResult {
+        CountResult {
+            accepted,
+            rejected: 1,
+        }
+    }
+
+    #[test]
+    fn test_spike_in_report() {
+        let names = vec![
+            "ERCC-00002".to_string(),
+            "ERCC-00003".to_string(),
+            "ERCC-00004".to_string(),
+        ];
+        let spike_ins = SpikeIns::new(&names, None).unwrap();
+        assert_eq!(spike_ins.find("ERCC-00003", &Record::new()), Some(1));
+        assert_eq!(spike_ins.find("chr1", &Record::new()), None);
+
+        let concentrations = HashMap::from([
+            ("ERCC-00002".to_string(), 10.0),
+            ("ERCC-00003".to_string(), 100.0),
+            ("ERCC-00004".to_string(), 1000.0),
+        ]);
+        let report = spike_ins.report(
+            &[counts(20), counts(200), counts(2000)],
+            &counts(22_200),
+            Some(&concentrations),
+        );
+        assert_eq!(report.total.accepted, 2220);
+        assert_eq!(report.total.rejected, 3);
+        assert_eq!(report.endogenous_ratio, Some(10.0));
+        let fit = report.fit.unwrap();
+        assert_eq!(fit.points, 3);
+        assert!((fit.slope - 1.0).abs() < 1e-9);
+        assert!((fit.intercept - 2f64.log10()).abs() < 1e-9);
+        assert!((fit.r_squared - 1.0).abs() < 1e-9);
+
+        // Without concentrations there is no fit, and without spike-in
+        // reads no ratio
+        let report = spike_ins.report(&[], &counts(100), None);
+        assert!(report.fit.is_none());
+        assert_eq!(report.endogenous_ratio, None);
+    }
+}
@@ -0,0 +1,316 @@
+use crate::classify::IntervalSet;
+use anyhow::Error;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+
+// A transcript and its exons, as sorted, non-overlapping 0-based, half-open
+// intervals
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    pub id: String,
+    pub gene_id: String,
+    pub seqname: String,
+    pub exons: Vec<(i64, i64)>,
+}
+
+impl Transcript {
+    // Whether a read with these aligned segments (split at introns of the
+    // alignment) could come from the transcript: the first and last
+    // segments lie within an exon, every splice junction of the read joins
+    // the end of one exon to the start of the next, and segments between
+    // junctions cover whole exons. A read running into an intron or past
+    // the end of the transcript is not compatible.
+    pub fn is_compatible(&self, segments: &[(i64, i64)]) -> bool {
+        let Some(&(first_start, _)) = segments.first() else {
+            return false;
+        };
+        let Some(mut exon) = self
+            .exons
+            .iter()
+            .position(|&(start, end)| start <= first_start && first_start < end)
+        else {
+            return false;
+        };
+        for (i, &(start, end)) in segments.iter().enumerate() {
+            let Some(&(exon_start, exon_end)) = self.exons.get(exon) else {
+                return false;
+            };
+            if start < exon_start || end > exon_end || (i > 0 && start != exon_start) {
+                return false;
+            }
+            if i + 1 < segments.len() {
+                if end != exon_end {
+                    return false;
+                }
+                exon += 1;
+            }
+        }
+        true
+    }
+}
+
+// The transcripts of an annotation, indexed by chromosome for finding the
+// ones compatible with a read
+#[derive(Debug, Clone)]
+pub struct TranscriptIndex {
+    transcripts: Vec<Transcript>,
+    spans: HashMap<String, IntervalSet>,
+}
+
+impl TranscriptIndex {
+    pub fn new(transcripts: Vec<Transcript>) -> Self {
+        let mut by_chrom: HashMap<String, Vec<(i64, i64, usize)>> = HashMap::new();
+        for (i, transcript) in transcripts.iter().enumerate() {
+            if let (Some(first), Some(last)) = (transcript.exons.first(), transcript.exons.last()) {
+                by_chrom
+                    .entry(transcript.seqname.clone())
+                    .or_default()
+                    .push((first.0, last.1, i));
+            }
+        }
+        TranscriptIndex {
+            transcripts,
+            spans: by_chrom
+                .into_iter()
+                .map(|(chrom, spans)| (chrom, IntervalSet::new(spans)))
+                .collect(),
+        }
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.transcripts.iter().map(|t| t.id.clone()).collect()
+    }
+
+    // Sorted indices of the transcripts compatible with a read on `chrom`,
+    // which is its equivalence class
+    pub fn compatible(&self, chrom: &str, segments: &[(i64, i64)]) -> Vec<u32> {
+        let Some(spans) = self.spans.get(chrom) else {
+            return vec![];
+        };
+        let mut candidates = vec![];
+        spans.overlapping(segments, &mut candidates);
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+            .into_iter()
+            .filter(|&i| self.transcripts[i].is_compatible(segments))
+            .map(|i| i as u32)
+            .collect()
+    }
+}
+
+// The transcripts an accepted read is compatible with. `mate_pos` is the
+// position of a mate that is yet to be read on the same chromosome.
+#[derive(Debug, Clone)]
+pub struct ReadClass {
+    pub qname: Vec<u8>,
+    pub pos: i64,
+    pub mate_pos: Option<i64>,
+    pub transcripts: Vec<u32>,
+}
+
+// Fragment counts of each equivalence class. The mates of a pair are joined
+// by read name, and the fragment is compatible with the transcripts both
+// mates are compatible with. A mate whose mate is not accepted counts alone.
+#[derive(Debug, Clone, Default)]
+pub struct FragmentClasses {
+    pub counts: HashMap<Vec<u32>, usize>,
+    // Mates waiting for their mate, by read name and by the mate's position
+    waiting: HashMap<Vec<u8>, Vec<u32>>,
+    by_mate_pos: BTreeSet<(i64, Vec<u8>)>,
+}
+
+impl FragmentClasses {
+    pub fn add(&mut self, read: ReadClass) {
+        if let Some(mate) = self.waiting.remove(&read.qname) {
+            let both = mate
+                .into_iter()
+                .filter(|transcript| read.transcripts.binary_search(transcript).is_ok())
+                .collect();
+            *self.counts.entry(both).or_insert(0) += 1;
+            return;
+        }
+        match read.mate_pos {
+            Some(mate_pos) if mate_pos >= read.pos => {
+                self.by_mate_pos.insert((mate_pos, read.qname.clone()));
+                self.waiting.insert(read.qname, read.transcripts);
+            }
+            _ => *self.counts.entry(read.transcripts).or_insert(0) += 1,
+        }
+    }
+
+    // Counts the waiting mates whose mate would have been read before `pos`
+    pub fn flush_before(&mut self, pos: i64) {
+        let kept = self.by_mate_pos.split_off(&(pos, vec![]));
+        for (_, qname) in std::mem::replace(&mut self.by_mate_pos, kept) {
+            if let Some(transcripts) = self.waiting.remove(&qname) {
+                *self.counts.entry(transcripts).or_insert(0) += 1;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: FragmentClasses) {
+        for (class, count) in other.counts {
+            *self.counts.entry(class).or_insert(0) += count;
+        }
+    }
+}
+
+// Accepted fragments compatible with at least one transcript, with none, and
+// the number of distinct equivalence classes
+#[derive(Debug, Clone, Serialize)]
+pub struct TranscriptCompatibility {
+    pub compatible: usize,
+    pub incompatible: usize,
+    pub equivalence_classes: usize,
+}
+
+// Fragment counts of each equivalence class, as written for EM quantifiers
+#[derive(Debug, Clone)]
+pub struct EquivalenceClassTable {
+    pub transcripts: Vec<String>,
+    // Classes ordered by decreasing count
+    pub classes: Vec<(Vec<u32>, usize)>,
+}
+
+impl EquivalenceClassTable {
+    // Splits the counts keyed by class into the table of non-empty classes
+    // and a summary. Fragments compatible with no transcript have an empty
+    // class.
+    pub fn new(
+        transcripts: Vec<String>,
+        counts: HashMap<Vec<u32>, usize>,
+    ) -> (Self, TranscriptCompatibility) {
+        let mut incompatible = 0;
+        let mut classes = vec![];
+        for (class, count) in counts {
+            if class.is_empty() {
+                incompatible += count;
+            } else {
+                classes.push((class, count));
+            }
+        }
+        classes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let summary = TranscriptCompatibility {
+            compatible: classes.iter().map(|(_, count)| count).sum(),
+            incompatible,
+            equivalence_classes: classes.len(),
+        };
+        (
+            EquivalenceClassTable {
+                transcripts,
+                classes,
+            },
+            summary,
+        )
+    }
+
+    // Writes the table in the eq_classes.txt layout of Salmon, without
+    // weights: the number of transcripts and of classes, the transcript
+    // names, then one line per class with its size, transcript indices and
+    // fragment count.
+    pub fn write(&self, out: &mut impl Write) -> Result<(), Error> {
+        writeln!(out, "{}", self.transcripts.len())?;
+        writeln!(out, "{}", self.classes.len())?;
+        for transcript in &self.transcripts {
+            writeln!(out, "{}", transcript)?;
+        }
+        for (class, count) in &self.classes {
+            write!(out, "{}", class.len())?;
+            for index in class {
+                write!(out, "\t{}", index)?;
+            }
+            writeln!(out, "\t{}", count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript(id: &str, exons: Vec<(i64, i64)>) -> Transcript {
+        Transcript {
+            id: id.to_string(),
+            gene_id: "gene1".to_string(),
+            seqname: "chr1".to_string(),
+            exons,
+        }
+    }
+
+    #[test]
+    fn test_is_compatible() {
+        let t = transcript("t1", vec![(100, 200), (300, 400), (500, 600)]);
+        // Within one exon
+        assert!(t.is_compatible(&[(120, 180)]));
+        // Spliced from exon 1 to exon 2, and across a whole middle exon
+        assert!(t.is_compatible(&[(150, 200), (300, 350)]));
+        assert!(t.is_compatible(&[(150, 200), (300, 400), (500, 550)]));
+        // Running into an intron, or off the end of the transcript
+        assert!(!t.is_compatible(&[(150, 250)]));
+        assert!(!t.is_compatible(&[(550, 650)]));
+        // Junction at the wrong place, or skipping an exon
+        assert!(!t.is_compatible(&[(150, 190), (300, 350)]));
+        assert!(!t.is_compatible(&[(150, 200), (500, 550)]));
+        assert!(!t.is_compatible(&[]));
+    }
+
+    #[test]
+    fn test_equivalence_classes() {
+        let index = TranscriptIndex::new(vec![
+            transcript("t1", vec![(100, 200), (300, 400)]),
+            transcript("t2", vec![(100, 200), (500, 600)]),
+            transcript("t3", vec![(150, 400)]),
+        ]);
+        assert_eq!(index.compatible("chr1", &[(120, 180)]), vec![0, 1]);
+        assert_eq!(index.compatible("chr1", &[(160, 180)]), vec![0, 1, 2]);
+        assert_eq!(index.compatible("chr1", &[(180, 200), (300, 320)]), vec![0]);
+        assert_eq!(index.compatible("chr1", &[(250, 260)]), vec![2]);
+        assert_eq!(index.compatible("chr2", &[(120, 180)]), Vec::<u32>::new());
+
+        let counts = HashMap::from([(vec![0, 1], 5), (vec![0], 7), (vec![], 2)]);
+        let (table, summary) = EquivalenceClassTable::new(index.ids(), counts);
+        assert_eq!(
+            (
+                summary.compatible,
+                summary.incompatible,
+                summary.equivalence_classes
+            ),
+            (12, 2, 2)
+        );
+        let mut out = vec![];
+        table.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "3\n2\nt1\nt2\nt3\n1\t0\t7\n2\t0\t1\t5\n"
+        );
+    }
+
+    #[test]
+    fn test_fragment_classes_join_mates() {
+        let read = |qname: &[u8], pos, mate_pos, transcripts: Vec<u32>| ReadClass {
+            qname: qname.to_vec(),
+            pos,
+            mate_pos,
+            transcripts,
+        };
+        let mut classes = FragmentClasses::default();
+        // Both mates accepted: counted once, with the shared transcripts
+        classes.add(read(b"pair", 100, Some(300), vec![0, 1, 2]));
+        classes.add(read(b"pair", 300, Some(100), vec![1, 2, 3]));
+        // The mate of a pair is never accepted: counted alone once passed
+        classes.add(read(b"lonely", 200, Some(400), vec![0]));
+        classes.flush_before(400);
+        assert!(classes.waiting.contains_key(b"lonely".as_slice()));
+        classes.flush_before(401);
+        // A single-end read
+        classes.add(read(b"single", 500, None, vec![0]));
+        assert!(classes.waiting.is_empty());
+        assert_eq!(
+            classes.counts,
+            HashMap::from([(vec![1, 2], 1), (vec![0], 2)])
+        );
+    }
+}